@@ -1,11 +1,12 @@
-use anyhow::Error;
+use anyhow::{bail, Error};
 use env_logger::Env;
 use log::{error, info};
 use structopt::StructOpt;
 
+use ffmpeg_decoder::{TranscodeOptions, WavSpec, WavWriter};
 use rodio::Sink;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 fn main() {
     env_logger::from_env(Env::default().default_filter_or("info")).init();
@@ -13,7 +14,7 @@ fn main() {
     let opts = Opts::from_args();
 
     let status = match opts.command {
-        Command::Convert { input } => decode_to_file(input),
+        Command::Convert { input, output } => decode_to_file(input, output),
         Command::Play { input } => play_file(input),
     };
 
@@ -23,18 +24,28 @@ fn main() {
     }
 }
 
-fn decode_to_file(input: PathBuf) -> Result<(), Error> {
-    let decoder = ffmpeg_decoder::Decoder::open(&input)?;
+fn decode_to_file(input: PathBuf, output: Option<PathBuf>) -> Result<(), Error> {
+    let out_path = output.unwrap_or_else(|| default_output_path(&input));
 
-    let samples = decoder.collect::<Vec<i16>>();
+    // Creating the output truncates it, which would destroy the input
+    if let (Ok(input), Ok(output)) = (input.canonicalize(), out_path.canonicalize()) {
+        if input == output {
+            bail!("Output {:?} is the input file", out_path);
+        }
+    }
 
-    let samples_u8 =
-        unsafe { std::slice::from_raw_parts(samples.as_ptr() as *const u8, samples.len() * 2) };
+    let is_wav = is_wav(&out_path);
 
-    let mut out_path: PathBuf = input;
-    out_path.set_extension("raw");
+    if is_wav {
+        let mut decoder = ffmpeg_decoder::Decoder::open(&input)?;
 
-    std::fs::write(&out_path, samples_u8)?;
+        let spec = WavSpec::from_decoder(&decoder);
+        let mut writer = WavWriter::create(&out_path, spec)?;
+        writer.write_decoder(&mut decoder)?;
+        writer.finish()?;
+    } else {
+        ffmpeg_decoder::transcode(&input, &out_path, TranscodeOptions::new())?;
+    }
 
     info!(
         "File successfully decoded, converted and saved to: {:?}",
@@ -44,6 +55,20 @@ fn decode_to_file(input: PathBuf) -> Result<(), Error> {
     Ok(())
 }
 
+/// The input with a `.wav` extension, or `.decoded.wav` if it already is a `.wav` file
+fn default_output_path(input: &Path) -> PathBuf {
+    if is_wav(input) {
+        input.with_extension("decoded.wav")
+    } else {
+        input.with_extension("wav")
+    }
+}
+
+fn is_wav(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("wav"))
+}
+
 fn play_file(input: PathBuf) -> Result<(), Error> {
     let decoder = ffmpeg_decoder::Decoder::open(&input)?;
 
@@ -62,10 +87,7 @@ fn log_error(e: Error) {
 }
 
 #[derive(StructOpt)]
-#[structopt(
-    name = "libav-decoder-cli",
-    about = "Convert input audio file sample format to signed 16bit"
-)]
+#[structopt(name = "libav-decoder-cli", about = "Convert or play audio files")]
 struct Opts {
     #[structopt(subcommand)]
     command: Command,
@@ -73,11 +95,15 @@ struct Opts {
 
 #[derive(StructOpt)]
 enum Command {
-    /// Convert file and save as `.raw` alongside input file
+    /// Convert file and save as `.wav` alongside input file
     Convert {
         /// Input audio file
         #[structopt(parse(from_os_str))]
         input: PathBuf,
+        /// Output file, encoded according to its extension. Defaults to the input with a `.wav`
+        /// extension, or `.decoded.wav` for `.wav` input. Must not be the input file.
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
     },
     /// Play input file
     Play {
@@ -0,0 +1,328 @@
+use crate::decoder::Decoder;
+use crate::error::Error;
+use crate::frame::{duration_to_samples, FrameBuffer, FrameSource};
+use crate::playlist::Track;
+
+use log::error;
+
+use std::collections::VecDeque;
+use std::f32::consts::FRAC_PI_2;
+use std::time::Duration;
+
+/// How the levels of the tracks change during a [`Crossfade`](struct.Crossfade.html)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CrossfadeCurve {
+    /// Gains add up to one. Correlated material keeps its level, but unrelated tracks dip in
+    /// loudness halfway through.
+    Linear,
+    /// Powers add up to one, so unrelated tracks keep a constant loudness
+    #[default]
+    EqualPower,
+}
+
+impl CrossfadeCurve {
+    /// Gains of the outgoing and incoming track, `progress` running from `0.0` to `1.0`
+    pub fn gains(self, progress: f32) -> (f32, f32) {
+        let progress = progress.clamp(0.0, 1.0);
+
+        match self {
+            CrossfadeCurve::Linear => (1.0 - progress, progress),
+            CrossfadeCurve::EqualPower => {
+                ((progress * FRAC_PI_2).cos(), (progress * FRAC_PI_2).sin())
+            }
+        }
+    }
+}
+
+/// Plays one decoder, then fades over to another ending up at full level
+///
+/// The fade starts `duration` before the end of the outgoing decoder, found from its duration.
+/// The outgoing samples are read `duration` ahead of playback, so if the duration is unknown or
+/// the decoder ends before the fade was to start, the fade runs over what was read ahead. The
+/// incoming decoder is converted to the channels and sample rate of the outgoing one.
+pub struct Crossfade {
+    outgoing: Decoder,
+    incoming: Track,
+    channels: usize,
+    curve: CrossfadeCurve,
+    /// Length of the fade in samples per channel
+    fade_len: u64,
+    /// Position of the outgoing decoder where the fade starts, `None` if its duration is unknown
+    fade_start: Option<u64>,
+    /// Outgoing samples read ahead of playback, up to the fade
+    lookahead: VecDeque<i16>,
+    /// Position of the outgoing decoder of the first sample in `lookahead`
+    lookahead_start: u64,
+    outgoing_finished: bool,
+    /// Samples per channel of the incoming decoder faded in so far
+    faded: u64,
+    incoming_frame: Vec<i16>,
+    incoming_frame_pos: usize,
+    incoming_finished: bool,
+    current_frame: FrameBuffer,
+    total_duration: Option<Duration>,
+}
+
+impl Crossfade {
+    /// Fade over `duration` with the `EqualPower` curve
+    pub fn new(
+        outgoing: Decoder,
+        incoming: Decoder,
+        duration: Duration,
+    ) -> Result<Crossfade, Error> {
+        let channels = outgoing._channels().max(1);
+        let sample_rate = outgoing._sample_rate();
+
+        let fade_len = duration_to_samples(duration, sample_rate);
+        let fade_start = outgoing
+            ._end_position()
+            .map(|end| end.saturating_sub(fade_len));
+
+        let total_duration = match (outgoing._total_duration(), incoming._total_duration()) {
+            (Some(outgoing), Some(incoming)) => {
+                Some(outgoing + incoming - duration.min(outgoing).min(incoming))
+            }
+            _ => None,
+        };
+
+        Ok(Crossfade {
+            outgoing,
+            incoming: Track::new(incoming, channels, sample_rate)?,
+            channels: channels as usize,
+            curve: CrossfadeCurve::default(),
+            fade_len,
+            fade_start,
+            lookahead: VecDeque::new(),
+            lookahead_start: 0,
+            outgoing_finished: false,
+            faded: 0,
+            incoming_frame: vec![],
+            incoming_frame_pos: 0,
+            incoming_finished: false,
+            current_frame: FrameBuffer::default(),
+            total_duration,
+        })
+    }
+
+    /// Defaults to `EqualPower`
+    pub fn curve(mut self, curve: CrossfadeCurve) -> Crossfade {
+        self.curve = curve;
+        self
+    }
+
+    /// Playback duration of both decoders, overlapping by the fade. `None` if either is
+    /// unknown.
+    pub fn duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+
+    /// Loads the next samples to play. Returns `false` once both decoders are exhausted.
+    fn load_mixed_frame(&mut self) -> bool {
+        loop {
+            if self.outgoing_finished {
+                let mut samples = match self.take_incoming() {
+                    Some(samples) => samples,
+                    None => return false,
+                };
+
+                // What's left of the fade if the outgoing decoder ended early
+                for frame in samples.chunks_exact_mut(self.channels) {
+                    if self.faded >= self.fade_len {
+                        break;
+                    }
+
+                    let (_, gain) = self.gains();
+                    for sample in frame {
+                        *sample = scale(*sample, gain);
+                    }
+                    self.faded += 1;
+                }
+
+                if self.store(samples) {
+                    return true;
+                }
+                continue;
+            }
+
+            let frame = match self.outgoing.next_frame() {
+                Some(frame) => frame,
+                None => {
+                    if let Some(error) = self.outgoing.take_error() {
+                        error!("Crossfading early: {}", error);
+                    }
+                    self.outgoing_finished = true;
+
+                    // Fade over what was read ahead, which may be less than the full fade
+                    if !self.lookahead.is_empty() {
+                        let samples: Vec<i16> = self.lookahead.drain(..).collect();
+                        self.fade_len = (samples.len() / self.channels) as u64;
+
+                        let samples = self.mix(samples, 0, 0);
+                        if self.store(samples) {
+                            return true;
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            if self.lookahead.is_empty() {
+                if let Some(timestamp) = frame.timestamp {
+                    self.lookahead_start = timestamp.sample_position.max(0) as u64;
+                }
+            }
+            self.lookahead.extend(frame.samples);
+
+            let end = self.lookahead_start + (self.lookahead.len() / self.channels) as u64;
+            let samples = match self.fade_start {
+                Some(fade_start) if end > fade_start => {
+                    let samples: Vec<i16> = self.lookahead.drain(..).collect();
+                    let start = self.lookahead_start;
+                    self.lookahead_start = end;
+
+                    self.mix(samples, start, fade_start)
+                }
+                _ => {
+                    let keep = self.fade_len as usize * self.channels;
+                    if self.lookahead.len() <= keep {
+                        continue;
+                    }
+
+                    let excess = self.lookahead.len() - keep;
+                    self.lookahead_start += (excess / self.channels) as u64;
+                    self.lookahead.drain(..excess).collect()
+                }
+            };
+
+            if self.store(samples) {
+                return true;
+            }
+        }
+    }
+
+    /// Mixes the incoming decoder into the outgoing `samples` from `fade_start` on, `start`
+    /// being the position of the first of them
+    fn mix(&mut self, mut samples: Vec<i16>, start: u64, fade_start: u64) -> Vec<i16> {
+        let skip = (fade_start.saturating_sub(start) as usize * self.channels).min(samples.len());
+
+        for frame in samples[skip..].chunks_exact_mut(self.channels) {
+            let (gain_out, gain_in) = self.gains();
+
+            for sample in frame {
+                let incoming = self.next_incoming_sample();
+                let mixed = f32::from(*sample) * gain_out + f32::from(incoming) * gain_in;
+
+                *sample = mixed
+                    .round()
+                    .clamp(f32::from(i16::MIN), f32::from(i16::MAX))
+                    as i16;
+            }
+            self.faded += 1;
+        }
+
+        samples
+    }
+
+    fn gains(&self) -> (f32, f32) {
+        let progress = if self.fade_len == 0 {
+            1.0
+        } else {
+            self.faded as f32 / self.fade_len as f32
+        };
+
+        self.curve.gains(progress)
+    }
+
+    /// The next sample of the incoming decoder, silence once it's exhausted
+    fn next_incoming_sample(&mut self) -> i16 {
+        if self.incoming_frame_pos >= self.incoming_frame.len() && !self.load_incoming() {
+            return 0;
+        }
+
+        let sample = self.incoming_frame[self.incoming_frame_pos];
+        self.incoming_frame_pos += 1;
+
+        sample
+    }
+
+    /// The not yet mixed samples of the incoming decoder, or its next frame
+    fn take_incoming(&mut self) -> Option<Vec<i16>> {
+        if self.incoming_frame_pos >= self.incoming_frame.len() && !self.load_incoming() {
+            return None;
+        }
+
+        let samples = self.incoming_frame[self.incoming_frame_pos..].to_vec();
+        self.incoming_frame_pos = self.incoming_frame.len();
+
+        Some(samples)
+    }
+
+    fn load_incoming(&mut self) -> bool {
+        while !self.incoming_finished {
+            match self.incoming.next_samples() {
+                Some(Ok(samples)) => {
+                    if !samples.is_empty() {
+                        self.incoming_frame = samples;
+                        self.incoming_frame_pos = 0;
+                        return true;
+                    }
+                }
+                Some(Err(error)) => {
+                    error!("Stopping incoming track: {}", error);
+                    self.incoming_finished = true;
+                }
+                None => self.incoming_finished = true,
+            }
+        }
+
+        false
+    }
+
+    /// Returns `false` if there's nothing to play in `samples`
+    fn store(&mut self, samples: Vec<i16>) -> bool {
+        if samples.is_empty() {
+            return false;
+        }
+
+        self.current_frame.set(samples);
+
+        true
+    }
+
+    pub(crate) fn _channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    pub(crate) fn _sample_rate(&self) -> u32 {
+        self.outgoing._sample_rate()
+    }
+}
+
+unsafe impl Send for Crossfade {}
+
+impl Iterator for Crossfade {
+    type Item = i16;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_sample()
+    }
+}
+
+impl FrameSource for Crossfade {
+    fn frame_buffer(&self) -> &FrameBuffer {
+        &self.current_frame
+    }
+
+    fn frame_buffer_mut(&mut self) -> &mut FrameBuffer {
+        &mut self.current_frame
+    }
+
+    fn load_frame(&mut self) -> bool {
+        self.load_mixed_frame()
+    }
+}
+
+fn scale(sample: i16, gain: f32) -> i16 {
+    (f32::from(sample) * gain).round() as i16
+}
@@ -1,58 +1,329 @@
-use crate::error::Error;
+use crate::demuxer::scan_stream_duration;
+use crate::error::{AvError, Error, ErrorKind, AVERROR_EAGAIN, AVERROR_EDEADLK, AVERROR_EOF};
+#[cfg(feature = "filter")]
+use crate::filter::{self, FilterGraph};
+use crate::format::{FormatContext, Packet, ReadFrameStatus, SharedInput, Stream};
+use crate::frame::{
+    duration_to_samples, samples_to_duration, AudioFrame, FrameBuffer, FrameSource, Position,
+    Timestamp,
+};
+use crate::metadata::{CoverArt, Metadata};
+use crate::replaygain::{apply_gain, ReplayGain, ReplayGainMode};
+use crate::resample::{AudioFormat, SwrContext};
+use crate::seek_index::SeekIndex;
+use crate::stream_selector::StreamSelector;
 
 use ffmpeg_sys_next::{
     self, av_frame_alloc, av_frame_free, av_frame_unref, av_freep, av_get_alt_sample_fmt,
-    av_get_bytes_per_sample, av_get_channel_layout_nb_channels, av_get_sample_fmt_name,
-    av_init_packet, av_packet_unref, av_read_frame, av_register_all, av_sample_fmt_is_planar,
-    av_samples_alloc, av_samples_get_buffer_size, avcodec_alloc_context3, avcodec_close,
-    avcodec_find_decoder, avcodec_free_context, avcodec_open2, avcodec_parameters_to_context,
-    avcodec_receive_frame, avcodec_send_packet, avformat_close_input, avformat_find_stream_info,
-    avformat_open_input, swr_alloc_set_opts, swr_convert, swr_get_out_samples, swr_init, AVCodec,
-    AVCodecContext, AVFormatContext, AVFrame, AVMediaType, AVPacket, AVSampleFormat, AVStream,
+    av_get_bytes_per_sample, av_get_sample_fmt_name, av_register_all, av_rescale_q,
+    av_sample_fmt_is_planar, av_samples_alloc, av_seek_frame, avcodec_alloc_context3,
+    avcodec_close, avcodec_find_decoder, avcodec_flush_buffers, avcodec_free_context,
+    avcodec_open2, avcodec_parameters_to_context, avcodec_receive_frame, avcodec_send_packet,
+    swr_convert, swr_get_out_samples, AVCodec, AVCodecContext, AVFrame, AVRational, AVSampleFormat,
+    AVSEEK_FLAG_BACKWARD, AVSEEK_FLAG_BYTE, AV_EF_IGNORE_ERR, AV_NOPTS_VALUE, AV_TIME_BASE_Q,
 };
-use std::ffi::{CStr, CString};
-use std::path::Path;
+#[cfg(feature = "filter")]
+use ffmpeg_sys_next::{av_frame_get_buffer, av_samples_set_silence};
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
 use std::ptr;
 use std::slice;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use log::{error, info};
+use log::{error, info, warn};
 
-const AVERROR_EOF: i32 = -0x20_464_F45;
-const AVERROR_EAGAIN: i32 = -11;
-const AVERROR_EDEADLK: i32 = -35;
 const DEFAULT_CONVERSION_FORMAT: AVSampleFormat = AVSampleFormat::AV_SAMPLE_FMT_S16;
+#[cfg(feature = "filter")]
+const MIN_TEMPO: f64 = 0.5;
+#[cfg(feature = "filter")]
+const MAX_TEMPO: f64 = 4.0;
+/// Semitones
+#[cfg(feature = "filter")]
+const MAX_PITCH: f64 = 12.0;
+/// Give up on a damaged file after this many unreadable packets in a row
+const MAX_CONSECUTIVE_READ_ERRORS: u32 = 64;
+/// Samples to start decoding before the target when seeking, as frames right after a seek may
+/// depend on the ones before, e.g. the MP3 bit reservoir
+const SEEK_PREROLL: u64 = 4608;
+
+/// What to do when a packet fails to decode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Stop decoding at the first error
+    #[default]
+    Strict,
+    /// Drop packets that can't be read or decoded and continue with the next one
+    SkipBadPackets,
+    /// Same as `SkipBadPackets`, but fill the dropped packets duration with silence
+    ConcealWithSilence,
+}
+
+/// Damage encountered while decoding with a tolerant [`ErrorPolicy`](enum.ErrorPolicy.html)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ErrorStats {
+    /// Number of packets that were dropped
+    pub skipped_packets: u64,
+    /// Number of samples (per channel) of silence inserted in place of dropped packets
+    pub concealed_samples: u64,
+    /// Duration of silence inserted in place of dropped packets
+    pub concealed_duration: Duration,
+}
+
+/// Options to open a [`Decoder`](struct.Decoder.html) with, created by [`Decoder::builder`](struct.Decoder.html#method.builder)
+#[derive(Debug, Clone)]
+pub struct DecoderBuilder {
+    path: PathBuf,
+    stream_selector: StreamSelector,
+    error_policy: ErrorPolicy,
+    replay_gain: Option<ReplayGainMode>,
+    exact_duration: bool,
+    seek_index: Option<SeekIndex>,
+    #[cfg(feature = "filter")]
+    filter: Option<String>,
+    #[cfg(feature = "filter")]
+    tempo: f64,
+    #[cfg(feature = "filter")]
+    pitch: f64,
+}
+
+impl DecoderBuilder {
+    /// Which audio stream to decode, defaults to the first
+    pub fn stream(mut self, selector: StreamSelector) -> DecoderBuilder {
+        self.stream_selector = selector;
+        self
+    }
+
+    /// How to handle damaged packets, defaults to [`ErrorPolicy::Strict`](enum.ErrorPolicy.html#variant.Strict)
+    pub fn error_policy(mut self, error_policy: ErrorPolicy) -> DecoderBuilder {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Scale samples by the ReplayGain of the file, lowered where needed to keep the tagged
+    /// peak from clipping. Files without gain tags are left untouched.
+    pub fn replay_gain(mut self, mode: ReplayGainMode) -> DecoderBuilder {
+        self.replay_gain = Some(mode);
+        self
+    }
+
+    /// Count the samples of all packets when opening, so [`Decoder::duration`](struct.Decoder.html#method.duration)
+    /// is exact instead of the containers estimate. Reads the whole file once, without
+    /// decoding it, see [`scan_duration`](fn.scan_duration.html).
+    pub fn exact_duration(mut self, exact_duration: bool) -> DecoderBuilder {
+        self.exact_duration = exact_duration;
+        self
+    }
+
+    /// Seek by the byte offsets of `index` instead of the containers own index or estimate,
+    /// which makes seeks exact in files such as VBR MP3 without a seek table. The index must be
+    /// built from the same file and stream, otherwise opening fails. When opening all streams,
+    /// only the stream it was built from uses it.
+    pub fn seek_index(mut self, index: SeekIndex) -> DecoderBuilder {
+        self.seek_index = Some(index);
+        self
+    }
+
+    /// Run the decoded audio through an avfilter graph, described as for ffmpegs `-af` option,
+    /// e.g. `loudnorm,atempo=1.25,highpass=f=80`. The returned samples keep the sample rate and
+    /// channels of the stream.
+    #[cfg(feature = "filter")]
+    pub fn filter(mut self, description: impl Into<String>) -> DecoderBuilder {
+        self.filter = Some(description.into());
+        self
+    }
+
+    /// Change the playback speed without changing the pitch, e.g. `1.5` plays 50% faster.
+    /// Clamped to `0.5..=4.0`.
+    #[cfg(feature = "filter")]
+    pub fn tempo(mut self, tempo: f64) -> DecoderBuilder {
+        self.tempo = tempo.clamp(MIN_TEMPO, MAX_TEMPO);
+        self
+    }
+
+    /// Shift the pitch by `semitones` without changing the speed. Clamped to one octave up or
+    /// down.
+    #[cfg(feature = "filter")]
+    pub fn pitch(mut self, semitones: f64) -> DecoderBuilder {
+        self.pitch = semitones.clamp(-MAX_PITCH, MAX_PITCH);
+        self
+    }
+
+    /// The filter graph to run, including tempo and pitch changes
+    #[cfg(feature = "filter")]
+    fn filter_description(&self, sample_rate: i32) -> Option<String> {
+        let filters = self
+            .filter
+            .iter()
+            .filter(|filter| !filter.is_empty())
+            .cloned()
+            .chain(filter::tempo_and_pitch(self.tempo, self.pitch, sample_rate))
+            .collect::<Vec<_>>();
+
+        if filters.is_empty() {
+            None
+        } else {
+            Some(filters.join(","))
+        }
+    }
+
+    pub fn open(self) -> Result<Decoder, Error> {
+        Decoder::open_with(self)
+    }
+
+    /// Open a decoder for every audio stream of the file, in the order of the file, each with
+    /// these options. The stream selector is ignored.
+    ///
+    /// The decoders share one reader of the file, so each packet is only read once. Packets
+    /// are queued for the decoders until they ask for them, so read all of them in step, e.g. a
+    /// frame of each in turn. A decoder falling over a thousand packets behind loses the oldest
+    /// of them, and dropping a decoder discards its packets. Seeking one of them seeks all of
+    /// them.
+    pub fn open_all(self) -> Result<Vec<Decoder>, Error> {
+        Decoder::open_all_with(self)
+    }
+}
+
+/// Where a decoder reads its packets from
+enum Input {
+    Owned(FormatContext),
+    /// Shared with the decoders of the other streams, see `DecoderBuilder::open_all`
+    Shared {
+        input: Arc<Mutex<SharedInput>>,
+        /// The seek of the input this decoder last followed
+        seek_generation: u64,
+    },
+}
 
 pub struct Decoder {
-    format_ctx: FormatContext,
+    input: Input,
     stream: Stream,
     codec_ctx: CodecContext,
     frame: Frame,
     packet: Packet,
     swr_ctx: Option<SwrContext>,
-    current_frame: Vec<u8>,
+    current_frame: FrameBuffer,
+    current_timestamp: Option<Timestamp>,
+    next_pts: Option<i64>,
+    /// Ignore frame timestamps, placing each frame right after the previous one
+    count_positions: bool,
+    frame_position: u64,
+    seek_target: Option<u64>,
+    /// Start and end of the samples to return, see `range`
+    range: Option<(u64, u64)>,
     first_frame_stored: bool,
+    /// The end of the stream was signalled to the codec
+    draining: bool,
+    finished: bool,
+    error_policy: ErrorPolicy,
+    consecutive_read_errors: u32,
+    last_frame_samples: i32,
+    skipped_packets: u64,
+    concealed_samples: u64,
+    error: Option<Error>,
+    #[cfg(feature = "filter")]
+    filter_graph: Option<FilterGraph>,
+    #[cfg(feature = "filter")]
+    tempo: f64,
+    /// ReplayGain scale factor applied to decoded samples
+    gain: Option<f64>,
+    /// Length of the stream counted from its packets, if requested
+    scanned_duration: Option<Duration>,
+    seek_index: Option<SeekIndex>,
 }
 
 impl Decoder {
     pub fn open(path: impl AsRef<Path>) -> Result<Decoder, Error> {
+        Decoder::builder(path).open()
+    }
+
+    pub fn builder(path: impl AsRef<Path>) -> DecoderBuilder {
+        DecoderBuilder {
+            path: path.as_ref().to_owned(),
+            stream_selector: StreamSelector::default(),
+            error_policy: ErrorPolicy::default(),
+            replay_gain: None,
+            exact_duration: false,
+            seek_index: None,
+            #[cfg(feature = "filter")]
+            filter: None,
+            #[cfg(feature = "filter")]
+            tempo: 1.0,
+            #[cfg(feature = "filter")]
+            pitch: 0.0,
+        }
+    }
+
+    fn open_with(builder: DecoderBuilder) -> Result<Decoder, Error> {
         unsafe { av_register_all() };
 
         // Open the file and get the format context
-        let format_ctx = FormatContext::open(&path.as_ref().display().to_string())?;
+        let format_ctx = FormatContext::open(&builder.path.display().to_string())?;
+
+        // Find the requested audio stream in file
+        format_ctx.find_stream_info()?;
+        let stream = format_ctx.select_audio_stream(&builder.stream_selector)?;
+
+        if let Some(index) = &builder.seek_index {
+            if !index.matches(&stream) {
+                return Err(Error::SeekIndexMismatch {
+                    stream_index: stream.index,
+                    sample_rate: stream.sample_rate(),
+                    index_stream: index.stream_index,
+                    index_sample_rate: index.sample_rate,
+                });
+            }
+        }
 
-        // Find first audio stream in file
+        Decoder::open_stream(&builder, Input::Owned(format_ctx), stream)
+    }
+
+    fn open_all_with(builder: DecoderBuilder) -> Result<Vec<Decoder>, Error> {
+        unsafe { av_register_all() };
+
+        let format_ctx = FormatContext::open(&builder.path.display().to_string())?;
         format_ctx.find_stream_info()?;
-        let stream = format_ctx.get_audio_stream()?;
 
+        let streams = format_ctx.audio_streams();
+        if streams.is_empty() {
+            return Err(Error::NoAudioStream {
+                path: format_ctx.path.clone(),
+            });
+        }
+
+        let indices = streams
+            .iter()
+            .map(|stream| stream.index)
+            .collect::<Vec<_>>();
+        let input = Arc::new(Mutex::new(SharedInput::new(format_ctx, &indices)));
+
+        streams
+            .into_iter()
+            .map(|stream| {
+                let input = Input::Shared {
+                    input: Arc::clone(&input),
+                    seek_generation: 0,
+                };
+
+                Decoder::open_stream(&builder, input, stream)
+            })
+            .collect()
+    }
+
+    fn open_stream(
+        builder: &DecoderBuilder,
+        input: Input,
+        stream: Stream,
+    ) -> Result<Decoder, Error> {
         // Get the streams codec
-        let codec = stream.get_codec()?;
+        let codec = Codec::find_decoder(&stream)?;
 
         // Setup codec context and intialize
         let codec_ctx = codec.get_context()?;
         codec_ctx.copy_parameters_from_stream(&stream)?;
         codec_ctx.request_non_planar_format();
-        codec_ctx.initialize()?;
+        codec_ctx.apply_error_policy(builder.error_policy);
+        codec_ctx.initialize(&stream)?;
 
         print_codec_info(&codec_ctx);
 
@@ -62,34 +333,353 @@ impl Decoder {
         // Initialize packet
         let packet = Packet::new();
 
+        // Setup the filter graph, which then also does the conversion
+        #[cfg(feature = "filter")]
+        let filter_graph = match builder.filter_description(codec_ctx.sample_rate()) {
+            Some(filter) => Some(FilterGraph::new(
+                &filter,
+                codec_ctx.audio_format(),
+                codec_ctx.audio_format(),
+                stream.time_base(),
+            )?),
+            None => None,
+        };
+
         // Initialize swr context, if conversion is needed
         let swr_ctx = if codec_ctx.sample_format() != DEFAULT_CONVERSION_FORMAT {
-            Some(SwrContext::new(&codec_ctx)?)
+            let output = AudioFormat {
+                sample_format: DEFAULT_CONVERSION_FORMAT,
+                ..codec_ctx.audio_format()
+            };
+
+            Some(SwrContext::new(codec_ctx.audio_format(), output)?)
         } else {
             None
         };
 
-        Ok(Decoder {
-            format_ctx,
+        let seek_index = builder
+            .seek_index
+            .clone()
+            .filter(|index| index.matches(&stream));
+
+        let mut decoder = Decoder {
+            input,
             stream,
             codec_ctx,
             frame,
             packet,
             swr_ctx,
-            current_frame: vec![],
+            current_frame: FrameBuffer::default(),
+            current_timestamp: None,
+            next_pts: None,
+            count_positions: false,
+            frame_position: 0,
+            seek_target: None,
+            range: None,
             first_frame_stored: false,
+            draining: false,
+            finished: false,
+            error_policy: builder.error_policy,
+            consecutive_read_errors: 0,
+            last_frame_samples: 0,
+            skipped_packets: 0,
+            concealed_samples: 0,
+            error: None,
+            #[cfg(feature = "filter")]
+            filter_graph,
+            #[cfg(feature = "filter")]
+            tempo: builder.tempo,
+            gain: None,
+            scanned_duration: None,
+            seek_index,
+        };
+
+        if builder.exact_duration {
+            let duration = scan_stream_duration(&builder.path, decoder.stream.index as usize)?.time;
+            info!("Duration:      {:?} (scanned)", duration);
+
+            decoder.scanned_duration = Some(duration);
+        }
+
+        if let Some(mode) = builder.replay_gain {
+            decoder.gain = decoder.replay_gain().scale(mode);
+
+            match decoder.gain {
+                Some(gain) => info!("ReplayGain:    {:.2} dB", 20.0 * gain.log10()),
+                None => info!("ReplayGain:    no tags"),
+            }
+        }
+
+        Ok(decoder)
+    }
+
+    /// Index of the decoded stream in the file
+    pub fn stream_index(&self) -> usize {
+        self.stream.index as usize
+    }
+
+    /// Packets dropped and silence inserted so far, see [`ErrorPolicy`](enum.ErrorPolicy.html)
+    pub fn error_stats(&self) -> ErrorStats {
+        ErrorStats {
+            skipped_packets: self.skipped_packets,
+            concealed_samples: self.concealed_samples,
+            concealed_duration: samples_to_duration(self.concealed_samples, self._sample_rate()),
+        }
+    }
+
+    /// The error that stopped decoding early, if any. Iteration ends on errors the
+    /// [`ErrorPolicy`](enum.ErrorPolicy.html) doesn't skip, this tells them apart from the end
+    /// of the stream.
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    /// Duration of the audio stream as stated by the container, if known. Exact if opened with
+    /// [`DecoderBuilder::exact_duration`](struct.DecoderBuilder.html#method.exact_duration).
+    pub fn duration(&self) -> Option<Duration> {
+        if self.scanned_duration.is_some() {
+            return self.scanned_duration;
+        }
+
+        let file_duration = self.with_format_ctx(FormatContext::duration);
+
+        let micros = match (self.stream.duration(), file_duration) {
+            (duration, _) if duration != AV_NOPTS_VALUE && duration > 0 => unsafe {
+                av_rescale_q(duration, self.stream.time_base(), AV_TIME_BASE_Q)
+            },
+            (_, duration) if duration != AV_NOPTS_VALUE && duration > 0 => duration,
+            _ => return None,
+        };
+
+        Some(Duration::from_micros(micros as u64))
+    }
+
+    /// Tags of the file and its audio stream. File level tags take precedence.
+    pub fn metadata(&self) -> Metadata {
+        let mut metadata = self.with_format_ctx(FormatContext::metadata);
+        metadata.merge(self.stream.metadata());
+
+        metadata
+    }
+
+    /// ReplayGain values from the tags of the file
+    pub fn replay_gain(&self) -> ReplayGain {
+        ReplayGain::from_metadata(&self.metadata())
+    }
+
+    /// The embedded cover picture, if any
+    pub fn cover_art(&self) -> Option<CoverArt> {
+        self.with_format_ctx(CoverArt::find)
+    }
+
+    /// Returns the not yet consumed samples of the current frame, or decodes the next frame if
+    /// all of them have been consumed. Can be mixed freely with `Iterator::next`.
+    pub fn next_frame(&mut self) -> Option<AudioFrame> {
+        self.follow_shared_seek();
+
+        if self.current_frame.is_exhausted() && !self.decode_next_frame() {
+            return None;
+        }
+
+        let consumed = (self.current_frame.pos / self.channels_usize()) as u64;
+        let timestamp = self.current_timestamp.map(|timestamp| {
+            timestamp.offset(consumed, self.stream.time_base(), self._sample_rate())
+        });
+
+        let samples = self.current_frame.take_remaining();
+
+        Some(AudioFrame {
+            samples,
+            channels: self._channels(),
+            sample_rate: self._sample_rate(),
+            timestamp,
         })
     }
 
-    fn read_next_frame(&mut self) -> ReadFrameStatus {
-        let status =
-            unsafe { av_read_frame(self.format_ctx.inner, self.packet.inner.as_mut_ptr()) };
+    /// Presentation timestamp of the most recently decoded frame
+    pub fn frame_timestamp(&self) -> Option<Timestamp> {
+        self.current_timestamp
+    }
 
-        match status {
-            AVERROR_EOF => ReadFrameStatus::Eof,
-            _ if status != 0 => ReadFrameStatus::Other(status),
-            _ => ReadFrameStatus::Ok,
+    /// Position of the next sample returned by the decoder, i.e. the samples consumed so far
+    pub fn position(&self) -> Position {
+        let consumed = (self.current_frame.pos / self.channels_usize()) as u64;
+
+        Position::new(self.frame_position + consumed, self._sample_rate())
+    }
+
+    /// Seeks to `position`. Decoding resumes from the nearest keyframe a little before it and
+    /// samples up to `position` are discarded, so the next sample returned is the one at
+    /// `position`.
+    ///
+    /// Without a [`SeekIndex`](struct.SeekIndex.html), where the demuxer lands depends on the
+    /// format. If it lands past `position`, e.g. for files without keyframes before it, decoding
+    /// resumes there and [`position`](#method.position) returns where it actually landed. Frames
+    /// decoded before the first one with a timestamp are discarded too, as it's unknown where
+    /// they lie.
+    pub fn seek(&mut self, position: Duration) -> Result<(), Error> {
+        self._seek_samples(duration_to_samples(position, self._sample_rate()))
+    }
+
+    /// Seeks to the sample at `target`, counted per channel
+    pub(crate) fn _seek_samples(&mut self, target: u64) -> Result<(), Error> {
+        let sample_rate = self._sample_rate();
+        let time_base = self.stream.time_base();
+        let start_time = match self.stream.start_time() {
+            AV_NOPTS_VALUE => 0,
+            start_time => start_time,
+        };
+        let to_pts = |samples: u64| unsafe {
+            start_time
+                + av_rescale_q(
+                    samples as i64,
+                    AVRational {
+                        num: 1,
+                        den: sample_rate as i32,
+                    },
+                    time_base,
+                )
+        };
+
+        let point = self.seek_index.as_ref().and_then(|index| {
+            index
+                .point_before(target.saturating_sub(SEEK_PREROLL))
+                .or_else(|| index.point_before(target))
+        });
+
+        let stream_index = self.stream.index;
+        let status = self.with_format_ctx(|format_ctx| match point {
+            Some(point) => unsafe {
+                av_seek_frame(
+                    format_ctx.inner,
+                    stream_index,
+                    point.offset as i64,
+                    AVSEEK_FLAG_BYTE,
+                )
+            },
+            None => unsafe {
+                av_seek_frame(
+                    format_ctx.inner,
+                    stream_index,
+                    to_pts(target.saturating_sub(SEEK_PREROLL)),
+                    AVSEEK_FLAG_BACKWARD,
+                )
+            },
+        });
+        if status < 0 {
+            return Err(Error::Seek {
+                position: samples_to_duration(target, sample_rate),
+                source: AvError::new(status),
+            });
+        }
+
+        // The decoders of the other streams follow once they notice
+        if let Input::Shared {
+            input,
+            seek_generation,
+        } = &mut self.input
+        {
+            let mut input = input.lock().unwrap();
+            input.clear_queues();
+            input.seek_generation += 1;
+            input.seek_position = samples_to_duration(target, sample_rate);
+
+            *seek_generation = input.seek_generation;
         }
+
+        // Timestamps after a byte seek are estimates of the demuxer, so positions are counted
+        // from the seek point instead
+        self.reset_to(target, point.map(|point| to_pts(point.sample)))
+    }
+
+    /// Restarts decoding after the input was seeked, returning samples from `target` on.
+    /// Positions are counted from `start_pts` if given, instead of taken from the frames.
+    fn reset_to(&mut self, target: u64, start_pts: Option<i64>) -> Result<(), Error> {
+        unsafe { avcodec_flush_buffers(self.codec_ctx.inner) };
+
+        #[cfg(feature = "filter")]
+        {
+            if let Some(filter_graph) = self.filter_graph.as_mut() {
+                filter_graph.reset()?;
+            }
+        }
+
+        self.current_frame.clear();
+        self.current_timestamp = None;
+        self.next_pts = None;
+        self.count_positions = false;
+        self.frame_position = target;
+        self.seek_target = Some(target);
+        self.first_frame_stored = false;
+        self.draining = false;
+        self.finished = false;
+        self.error = None;
+
+        if let Some(pts) = start_pts {
+            self.next_pts = Some(pts);
+            self.count_positions = true;
+        }
+
+        Ok(())
+    }
+
+    /// Seeks along with the decoder of another stream that was seeked, if reading from a
+    /// shared input
+    fn follow_shared_seek(&mut self) {
+        let position = match &mut self.input {
+            Input::Shared {
+                input,
+                seek_generation,
+            } => {
+                let input = input.lock().unwrap();
+                if input.seek_generation == *seek_generation {
+                    return;
+                }
+
+                *seek_generation = input.seek_generation;
+                input.seek_position
+            }
+            Input::Owned(_) => return,
+        };
+
+        let target = duration_to_samples(position, self._sample_rate());
+        if let Err(error) = self.reset_to(target, None) {
+            self.stop_with_error(error);
+            self.finish();
+        }
+    }
+
+    /// Reads the next packet of the file into `packet`, of any stream if not shared
+    fn read_packet(&mut self) -> ReadFrameStatus {
+        match &self.input {
+            Input::Owned(format_ctx) => format_ctx.read_packet(&mut self.packet),
+            Input::Shared { input, .. } => input
+                .lock()
+                .unwrap()
+                .read_packet(self.stream.index, &mut self.packet),
+        }
+    }
+
+    fn with_format_ctx<T>(&self, f: impl FnOnce(&FormatContext) -> T) -> T {
+        match &self.input {
+            Input::Owned(format_ctx) => f(format_ctx),
+            Input::Shared { input, .. } => f(&input.lock().unwrap().format_ctx),
+        }
+    }
+
+    /// Only return the samples from `start` up to `end`. Seeks to `start`, and decoding ends
+    /// with the sample before `end`, so both ends are sample accurate. The end is kept when
+    /// seeking within the range afterwards.
+    pub fn range(&mut self, start: Duration, end: Duration) -> Result<(), Error> {
+        self.seek(start)?;
+
+        let sample_rate = self._sample_rate();
+        let start = duration_to_samples(start, sample_rate);
+        let end = duration_to_samples(end, sample_rate).max(start);
+
+        self.range = Some((start, end));
+
+        Ok(())
     }
 
     fn send_packet_for_decoding(&mut self) -> SendPacketStatus {
@@ -109,22 +699,29 @@ impl Decoder {
             0 => ReceiveFrameStatus::Ok,
             AVERROR_EAGAIN => ReceiveFrameStatus::Again,
             AVERROR_EDEADLK => ReceiveFrameStatus::Deadlk,
+            AVERROR_EOF => ReceiveFrameStatus::Eof,
             _ => ReceiveFrameStatus::Other(status),
         }
     }
 
-    fn convert_and_store_frame(&mut self) {
+    fn convert_and_store_frame(&mut self) -> Result<(), Error> {
+        #[cfg(feature = "filter")]
+        {
+            if self.filter_graph.is_some() {
+                return self.filter_and_store_frame();
+            }
+        }
+
         let num_samples = self.frame.num_samples();
-        let channel_layout = self.frame.channel_layout();
-        let num_channels = unsafe { av_get_channel_layout_nb_channels(channel_layout) };
+        self.last_frame_samples = num_samples;
+        let num_channels = self.frame.channels();
 
         let extended_data = self.frame.extended_data();
 
         let mut out_buf = std::ptr::null_mut::<u8>();
 
-        let out_slice = if self.swr_ctx.is_some() {
-            let out_samples =
-                unsafe { swr_get_out_samples(self.swr_ctx.as_ref().unwrap().inner, num_samples) };
+        let out_slice = if let Some(swr_ctx) = &self.swr_ctx {
+            let out_samples = unsafe { swr_get_out_samples(swr_ctx.inner, num_samples) };
 
             unsafe {
                 av_samples_alloc(
@@ -137,9 +734,9 @@ impl Decoder {
                 )
             };
 
-            unsafe {
+            let converted_samples = unsafe {
                 swr_convert(
-                    self.swr_ctx.as_ref().unwrap().inner,
+                    swr_ctx.inner,
                     &mut out_buf,
                     out_samples,
                     extended_data,
@@ -147,31 +744,24 @@ impl Decoder {
                 )
             };
 
-            let out_size = unsafe {
-                av_samples_get_buffer_size(
-                    ptr::null_mut(),
-                    num_channels,
-                    out_samples,
-                    DEFAULT_CONVERSION_FORMAT,
-                    0,
+            unsafe {
+                slice::from_raw_parts(
+                    out_buf as *const i16,
+                    (converted_samples.max(0) * num_channels) as usize,
                 )
-            };
-
-            unsafe { slice::from_raw_parts(out_buf, out_size as usize) }
+            }
         } else {
             unsafe {
                 slice::from_raw_parts(
-                    extended_data.as_ref().unwrap().as_ref().unwrap(),
-                    self.frame.inner.as_ref().unwrap().linesize[0] as usize,
+                    *extended_data as *const i16,
+                    (num_samples * num_channels) as usize,
                 )
             }
         };
 
-        if !self.current_frame.is_empty() {
-            self.current_frame.drain(..);
-        }
-
-        self.current_frame.extend_from_slice(out_slice);
+        self.current_frame.samples.clear();
+        self.current_frame.samples.extend_from_slice(out_slice);
+        self.store_timestamp(self.frame.best_effort_timestamp(), num_samples as i64);
 
         if self.swr_ctx.is_some() {
             // Free samples buffer
@@ -179,231 +769,546 @@ impl Decoder {
         }
 
         unsafe { av_frame_unref(self.frame.inner) };
+
+        Ok(())
+    }
+
+    #[cfg(feature = "filter")]
+    fn filter_and_store_frame(&mut self) -> Result<(), Error> {
+        self.last_frame_samples = self.frame.num_samples();
+
+        unsafe {
+            self.frame.inner.as_mut().unwrap().pts =
+                self.frame.best_effort_timestamp().unwrap_or(AV_NOPTS_VALUE)
+        };
+
+        // Taken from the frame, as the decoded format may change mid-stream
+        let format = self.frame.audio_format();
+        let status = self
+            .filter_graph
+            .as_mut()
+            .unwrap()
+            .send_frame(self.frame.inner, format);
+
+        unsafe { av_frame_unref(self.frame.inner) };
+        status?;
+
+        self.store_filtered_samples()
+    }
+
+    /// Stores whatever the filter graph has ready, which may be nothing if it buffers
+    #[cfg(feature = "filter")]
+    fn store_filtered_samples(&mut self) -> Result<(), Error> {
+        let (samples, pts) = self.filter_graph.as_mut().unwrap().receive_samples()?;
+        let num_samples = (samples.len() / self.channels_usize()) as i64;
+
+        self.current_frame.samples = samples;
+        if num_samples > 0 {
+            self.store_timestamp(pts, num_samples);
+        }
+
+        Ok(())
+    }
+
+    /// Stores the samples the filter graph holds back at the end of the stream. Returns `false`
+    /// if there are none.
+    #[cfg(feature = "filter")]
+    fn flush_filter(&mut self) -> bool {
+        let flushable = self.error.is_none()
+            && self
+                .filter_graph
+                .as_ref()
+                .is_some_and(|filter_graph| !filter_graph.is_flushed());
+        if !flushable {
+            return false;
+        }
+
+        let status = self
+            .filter_graph
+            .as_mut()
+            .unwrap()
+            .flush()
+            .and_then(|()| self.store_filtered_samples());
+        if let Err(error) = status {
+            self.stop_with_error(error);
+            return false;
+        }
+
+        !self.current_frame.samples.is_empty()
+    }
+
+    fn stop_with_error(&mut self, error: Error) {
+        error!("{}", error);
+        self.error = Some(error);
     }
 
     fn frame_for_stream(&self) -> bool {
-        unsafe { self.packet.inner.as_ptr().as_ref().unwrap().stream_index == self.stream.index }
+        self.packet.stream_index() == self.stream.index
     }
 
     fn reset_packet(&mut self) {
-        unsafe { av_packet_unref(self.packet.inner.as_mut_ptr()) };
+        self.packet.reset();
     }
 
-    fn next_sample(&mut self) -> i16 {
-        let sample_u8: [u8; 2] = [self.current_frame.remove(0), self.current_frame.remove(0)];
+    /// Timestamps the current frame. Frames without a timestamp of their own are placed right
+    /// after the previous frame.
+    fn store_timestamp(&mut self, pts: Option<i64>, num_samples: i64) {
+        let time_base = self.stream.time_base();
+        let start_time = self.stream.start_time();
+        let sample_rate = self._sample_rate();
+
+        let pts = if self.count_positions { None } else { pts };
+        let pts = pts.or(self.next_pts);
+
+        self.current_timestamp =
+            pts.map(|pts| Timestamp::from_pts(pts, start_time, time_base, sample_rate));
+        self.next_pts = pts.map(|pts| {
+            pts + unsafe {
+                av_rescale_q(
+                    num_samples,
+                    AVRational {
+                        num: 1,
+                        den: sample_rate as i32,
+                    },
+                    time_base,
+                )
+            }
+        });
+    }
 
-        ((sample_u8[1] as i16) << 8) | sample_u8[0] as i16
+    fn channels_usize(&self) -> usize {
+        self._channels().max(1) as usize
     }
 
-    fn process_next_frame(&mut self) -> Option<Result<(), Error>> {
-        match self.read_next_frame() {
-            ReadFrameStatus::Ok => {}
-            ReadFrameStatus::Eof => {
-                return None;
-            }
-            ReadFrameStatus::Other(status) => {
-                error!("{}", Error::ReadFrame(status));
-                return None;
+    fn packet_duration(&self) -> i64 {
+        self.packet.get().duration
+    }
+
+    /// Applies the error policy to a packet that failed to read or decode. Returns `false`
+    /// if decoding should stop.
+    fn skip_bad_packet(&mut self, error: Error, packet_duration: i64) -> bool {
+        if self.error_policy == ErrorPolicy::Strict || error.kind() == Some(ErrorKind::OutOfMemory)
+        {
+            self.stop_with_error(error);
+            return false;
+        }
+
+        warn!("Skipping bad packet: {}", error);
+        self.skipped_packets += 1;
+
+        if self.error_policy == ErrorPolicy::ConcealWithSilence {
+            if let Err(error) = self.conceal_packet(packet_duration) {
+                self.stop_with_error(error);
+                return false;
             }
         }
 
-        if !self.frame_for_stream() {
-            self.reset_packet();
-            return self.process_next_frame();
+        true
+    }
+
+    /// Stores silence in place of a dropped packet. The duration is taken from the packet if
+    /// known, otherwise the codec frame size or the previous frame is used. With a filter graph
+    /// the silence is filtered like decoded audio.
+    fn conceal_packet(&mut self, packet_duration: i64) -> Result<(), Error> {
+        let sample_rate = self.codec_ctx.sample_rate();
+
+        let num_samples = if packet_duration > 0 {
+            unsafe {
+                av_rescale_q(
+                    packet_duration,
+                    self.stream.time_base(),
+                    AVRational {
+                        num: 1,
+                        den: sample_rate,
+                    },
+                )
+            }
+        } else if self.codec_ctx.frame_size() > 0 {
+            self.codec_ctx.frame_size() as i64
+        } else {
+            self.last_frame_samples as i64
+        };
+
+        if num_samples <= 0 {
+            return Ok(());
         }
 
-        match self.send_packet_for_decoding() {
-            SendPacketStatus::Ok => self.reset_packet(),
-            SendPacketStatus::Other(status) => {
-                error!("{}", Error::SendPacket(status));
-                return None;
+        self.concealed_samples += num_samples as u64;
+
+        #[cfg(feature = "filter")]
+        {
+            if self.filter_graph.is_some() {
+                self.frame
+                    .set_silence(self.codec_ctx.audio_format(), num_samples as i32)?;
+                return self.filter_and_store_frame();
             }
         }
 
-        match self.receive_decoded_frame() {
-            ReceiveFrameStatus::Ok => {}
-            ReceiveFrameStatus::Again | ReceiveFrameStatus::Deadlk => {
-                return self.process_next_frame()
+        let len = num_samples as usize * self.channels_usize();
+
+        self.current_frame.samples.clear();
+        self.current_frame.samples.resize(len, 0);
+        self.store_timestamp(None, num_samples);
+
+        Ok(())
+    }
+
+    fn process_next_frame(&mut self) -> Option<Result<(), Error>> {
+        loop {
+            match self.read_packet() {
+                ReadFrameStatus::Ok => self.consecutive_read_errors = 0,
+                ReadFrameStatus::Eof => {
+                    return None;
+                }
+                ReadFrameStatus::Other(status) => {
+                    self.consecutive_read_errors += 1;
+
+                    let error = Error::ReadFrame(AvError::new(status));
+                    if self.consecutive_read_errors >= MAX_CONSECUTIVE_READ_ERRORS {
+                        error!(
+                            "Giving up after {} unreadable packets: {}",
+                            MAX_CONSECUTIVE_READ_ERRORS, error
+                        );
+                        self.error = Some(error);
+                        return None;
+                    }
+                    if !self.skip_bad_packet(error, 0) {
+                        return None;
+                    }
+
+                    if !self.current_frame.samples.is_empty() {
+                        return Some(Ok(()));
+                    }
+                    continue;
+                }
             }
-            ReceiveFrameStatus::Other(status) => {
-                error!("{}", Error::ReceiveFrame(status));
+
+            if !self.frame_for_stream() {
+                self.reset_packet();
+                continue;
+            }
+
+            let packet_duration = self.packet_duration();
+
+            match self.send_packet_for_decoding() {
+                SendPacketStatus::Ok => self.reset_packet(),
+                SendPacketStatus::Other(status) => {
+                    self.reset_packet();
+
+                    let error = Error::SendPacket {
+                        stream_index: self.stream.index,
+                        source: AvError::new(status),
+                    };
+                    if !self.skip_bad_packet(error, packet_duration) {
+                        return None;
+                    }
+
+                    if !self.current_frame.samples.is_empty() {
+                        return Some(Ok(()));
+                    }
+                    continue;
+                }
+            }
+
+            match self.receive_decoded_frame() {
+                ReceiveFrameStatus::Ok => {}
+                ReceiveFrameStatus::Again
+                | ReceiveFrameStatus::Deadlk
+                | ReceiveFrameStatus::Eof => continue,
+                ReceiveFrameStatus::Other(status) => {
+                    let error = Error::ReceiveFrame {
+                        stream_index: self.stream.index,
+                        source: AvError::new(status),
+                    };
+                    if !self.skip_bad_packet(error, packet_duration) {
+                        return None;
+                    }
+
+                    if !self.current_frame.samples.is_empty() {
+                        return Some(Ok(()));
+                    }
+                    continue;
+                }
+            }
+
+            if let Err(error) = self.convert_and_store_frame() {
+                self.stop_with_error(error);
                 return None;
             }
+
+            if !self.current_frame.samples.is_empty() {
+                return Some(Ok(()));
+            }
         }
+    }
 
-        self.convert_and_store_frame();
+    /// Decodes the next frame into `current_frame`, skipping what lies before a pending seek
+    /// target. Returns `false` once the stream is exhausted.
+    fn decode_next_frame(&mut self) -> bool {
+        self.follow_shared_seek();
 
-        Some(Ok(()))
-    }
+        let previous_samples = (self.current_frame.samples.len() / self.channels_usize()) as u64;
 
-    fn cleanup(&mut self) {
-        // Drain the decoder.
-        drain_decoder(self.codec_ctx.inner).unwrap();
+        loop {
+            if !self.decode_frame() {
+                return false;
+            }
 
-        unsafe {
-            // Free all data used by the frame.
-            av_frame_free(&mut self.frame.inner);
+            if self.skip_to_seek_target() {
+                break;
+            }
+        }
 
-            // Close the context and free all data associated to it, but not the context itself.
-            avcodec_close(self.codec_ctx.inner);
+        // Samples presented before the start of the stream are at position zero
+        self.frame_position = match self.current_timestamp {
+            Some(timestamp) => timestamp.sample_position.max(0) as u64,
+            None => self.frame_position + previous_samples,
+        };
 
-            // Free the context itself.
-            avcodec_free_context(&mut self.codec_ctx.inner);
+        if !self.trim_to_range_end() {
+            return false;
+        }
 
-            // Close the input.
-            avformat_close_input(&mut self.format_ctx.inner);
+        if let Some(gain) = self.gain {
+            apply_gain(&mut self.current_frame.samples, gain);
         }
-    }
 
-    pub(crate) fn _current_frame_len(&self) -> Option<usize> {
-        Some(self.current_frame.len())
+        true
     }
 
-    pub(crate) fn _channels(&self) -> u16 {
-        self.codec_ctx.channels() as _
-    }
+    /// Drops samples from the end of the range on. Returns `false` if the whole frame was
+    /// dropped, which ends decoding.
+    fn trim_to_range_end(&mut self) -> bool {
+        let end = match self.range {
+            Some((_, end)) => end,
+            None => return true,
+        };
 
-    pub(crate) fn _sample_rate(&self) -> u32 {
-        self.codec_ctx.sample_rate() as _
-    }
+        let frame_samples = (self.current_frame.samples.len() / self.channels_usize()) as u64;
+        let keep = end.saturating_sub(self.frame_position).min(frame_samples) as usize;
+        self.current_frame
+            .samples
+            .truncate(keep * self.channels_usize());
 
-    pub(crate) fn _total_duration(&self) -> Option<Duration> {
-        //TODO let duration = self.stream.duration();
-        None
-    }
-}
+        if self.current_frame.is_exhausted() {
+            self.current_frame.clear();
+            self.finished = true;
 
-unsafe impl Send for Decoder {}
+            return false;
+        }
 
-impl Iterator for Decoder {
-    type Item = i16;
+        true
+    }
 
-    #[inline]
-    fn next(&mut self) -> Option<Self::Item> {
-        if !self.first_frame_stored {
-            if self.process_next_frame().is_none() {
-                self.cleanup();
-                return None;
-            }
+    /// Drops samples before the pending seek target. Returns `false` if the whole frame was
+    /// dropped.
+    fn skip_to_seek_target(&mut self) -> bool {
+        let target = match self.seek_target {
+            Some(target) => target,
+            None => return true,
+        };
 
-            self.first_frame_stored = true;
+        // Frames right after the seek point may lack a timestamp, and as it's unknown where the
+        // demuxer landed they're dropped until a frame tells the position
+        let timestamp = match self.current_timestamp {
+            Some(timestamp) => timestamp,
+            None => return false,
+        };
 
-            return Some(self.next_sample());
+        let frame_samples = (self.current_frame.samples.len() / self.channels_usize()) as i64;
+        let target = target as i64;
+        if timestamp.sample_position + frame_samples <= target {
+            return false;
         }
 
-        if !self.current_frame.is_empty() {
-            return Some(self.next_sample());
+        let skip = (target - timestamp.sample_position).max(0) as usize;
+        self.current_frame.pos = skip * self.channels_usize();
+        self.seek_target = None;
+
+        true
+    }
+
+    /// Decodes the next frame into `current_frame`. Returns `false` once the stream is exhausted,
+    /// otherwise at least one sample has been stored.
+    fn decode_frame(&mut self) -> bool {
+        if self.finished {
+            return false;
         }
 
-        match self.receive_decoded_frame() {
-            ReceiveFrameStatus::Ok => {
-                self.convert_and_store_frame();
-                Some(self.next_sample())
-            }
-            ReceiveFrameStatus::Again | ReceiveFrameStatus::Deadlk => {
-                if self.process_next_frame().is_none() {
-                    self.cleanup();
-                    return None;
+        self.current_frame.clear();
+
+        if self.first_frame_stored {
+            match self.receive_decoded_frame() {
+                ReceiveFrameStatus::Ok => {
+                    if let Err(error) = self.convert_and_store_frame() {
+                        self.stop_with_error(error);
+                        self.finish();
+                        return false;
+                    }
+                }
+                ReceiveFrameStatus::Again
+                | ReceiveFrameStatus::Deadlk
+                | ReceiveFrameStatus::Eof => {}
+                ReceiveFrameStatus::Other(status) => {
+                    let error = Error::ReceiveFrame {
+                        stream_index: self.stream.index,
+                        source: AvError::new(status),
+                    };
+                    if !self.skip_bad_packet(error, 0) {
+                        self.finish();
+                        return false;
+                    }
                 }
+            }
+
+            if !self.current_frame.samples.is_empty() {
+                return true;
+            }
+        }
+
+        self.first_frame_stored = true;
 
-                Some(self.next_sample())
+        if self.process_next_frame().is_none() {
+            if self.error.is_none() && self.drain_frame() {
+                return true;
             }
-            ReceiveFrameStatus::Other(status) => {
-                error!("{}", Error::ReceiveFrame(status));
-                self.cleanup();
-                None
+
+            #[cfg(feature = "filter")]
+            {
+                if self.flush_filter() {
+                    return true;
+                }
             }
+
+            self.finish();
+            return false;
         }
+
+        true
     }
-}
 
-struct FormatContext {
-    inner: *mut AVFormatContext,
-}
+    /// Receives the next of the frames the codec still holds at the end of the stream, so the
+    /// end of the stream isn't cut off. Returns `false` once all have been received.
+    fn drain_frame(&mut self) -> bool {
+        if !self.draining {
+            self.draining = true;
 
-impl FormatContext {
-    fn open(path: &str) -> Result<FormatContext, Error> {
-        let mut inner = std::ptr::null_mut::<AVFormatContext>();
+            if let Err(error) = drain_decoder(self.codec_ctx.inner) {
+                warn!("{}", error);
+                return false;
+            }
+        }
 
-        let path = CString::new(path).unwrap();
+        while let ReceiveFrameStatus::Ok = self.receive_decoded_frame() {
+            if let Err(error) = self.convert_and_store_frame() {
+                self.stop_with_error(error);
+                return false;
+            }
 
-        let status = unsafe {
-            avformat_open_input(
-                &mut inner,
-                path.as_ptr(),
-                std::ptr::null_mut(),
-                std::ptr::null_mut(),
-            )
-        };
-        if status != 0 {
-            return Err(Error::InitializeFormatContext);
+            if !self.current_frame.samples.is_empty() {
+                return true;
+            }
         }
 
-        Ok(FormatContext { inner })
+        false
     }
 
-    /// Look at first few frames to determine stream info
-    fn find_stream_info(&self) -> Result<(), Error> {
-        let status = unsafe { avformat_find_stream_info(self.inner, ptr::null_mut()) };
-        if status < 0 {
-            return Err(Error::FindStreamInfo);
-        }
-        Ok(())
+    fn finish(&mut self) {
+        self.finished = true;
     }
 
-    ///  Get the first audio stream
-    fn get_audio_stream(&self) -> Result<Stream, Error> {
-        let num_streams = unsafe { self.inner.as_ref().unwrap().nb_streams };
-        let streams = unsafe { self.inner.as_ref().unwrap().streams };
+    pub(crate) fn _channels(&self) -> u16 {
+        self.codec_ctx.channels() as _
+    }
 
-        let streams = unsafe { slice::from_raw_parts(streams, num_streams as usize) };
+    /// Layout of the returned samples, as `AV_CH_*` flags
+    pub(crate) fn _channel_layout(&self) -> u64 {
+        self.codec_ctx.audio_format().channel_layout
+    }
 
-        let stream_idx = find_audio_stream(streams)?;
+    pub(crate) fn _sample_rate(&self) -> u32 {
+        self.codec_ctx.sample_rate() as _
+    }
 
-        Ok(Stream::new(streams[0], stream_idx))
+    /// Unit of the `pts` of returned timestamps
+    pub(crate) fn _time_base(&self) -> AVRational {
+        self.stream.time_base()
     }
-}
 
-struct SwrContext {
-    inner: *mut ffmpeg_sys_next::SwrContext,
-}
+    /// Position at which playback stops, the end of the range or the stream
+    pub(crate) fn _end_position(&self) -> Option<u64> {
+        let end = self
+            .duration()
+            .map(|duration| duration_to_samples(duration, self._sample_rate()));
 
-impl SwrContext {
-    fn new(codec_ctx: &CodecContext) -> Result<SwrContext, Error> {
-        let swr_ctx: *mut ffmpeg_sys_next::SwrContext = unsafe {
-            swr_alloc_set_opts(
-                ptr::null_mut(),
-                codec_ctx.channel_layout() as i64,
-                DEFAULT_CONVERSION_FORMAT,
-                codec_ctx.sample_rate(),
-                codec_ctx.channel_layout() as i64,
-                codec_ctx.sample_format(),
-                codec_ctx.sample_rate(),
-                0,
-                ptr::null_mut(),
-            )
+        match self.range {
+            Some((_, range_end)) => Some(end.map_or(range_end, |end| end.min(range_end))),
+            None => end,
+        }
+    }
+
+    /// Playback duration, i.e. taking the range and a tempo change into account
+    pub(crate) fn _total_duration(&self) -> Option<Duration> {
+        let duration = match self.range {
+            Some((start, _)) => self
+                ._end_position()
+                .map(|end| samples_to_duration(end.saturating_sub(start), self._sample_rate())),
+            None => self.duration(),
         };
 
-        let status = unsafe { swr_init(swr_ctx) };
-        if status != 0 {
-            return Err(Error::InitializeSwr);
+        #[cfg(feature = "filter")]
+        {
+            duration.map(|duration| Duration::from_secs_f64(duration.as_secs_f64() / self.tempo))
+        }
+
+        #[cfg(not(feature = "filter"))]
+        {
+            duration
+        }
+    }
+}
+
+unsafe impl Send for Decoder {}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        if let Input::Shared { input, .. } = &self.input {
+            input.lock().unwrap().remove_stream(self.stream.index);
         }
 
-        Ok(SwrContext { inner: swr_ctx })
+        unsafe {
+            // Free all data used by the frame.
+            av_frame_free(&mut self.frame.inner);
+
+            // Close the context and free all data associated to it, but not the context itself.
+            avcodec_close(self.codec_ctx.inner);
+
+            // Free the context itself.
+            avcodec_free_context(&mut self.codec_ctx.inner);
+        }
     }
 }
 
-struct Packet {
-    inner: std::mem::MaybeUninit<AVPacket>,
+impl Iterator for Decoder {
+    type Item = i16;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_sample()
+    }
 }
 
-impl Packet {
-    fn new() -> Packet {
-        let mut packet = std::mem::MaybeUninit::uninit();
+impl FrameSource for Decoder {
+    fn frame_buffer(&self) -> &FrameBuffer {
+        &self.current_frame
+    }
 
-        unsafe { av_init_packet(packet.as_mut_ptr()) };
+    fn frame_buffer_mut(&mut self) -> &mut FrameBuffer {
+        &mut self.current_frame
+    }
 
-        Packet { inner: packet }
+    fn load_frame(&mut self) -> bool {
+        self.decode_next_frame()
     }
 }
 
@@ -426,41 +1331,67 @@ impl Frame {
         unsafe { self.inner.as_ref().unwrap().nb_samples }
     }
 
-    fn channel_layout(&self) -> u64 {
-        unsafe { self.inner.as_ref().unwrap().channel_layout }
+    fn channels(&self) -> i32 {
+        unsafe { self.inner.as_ref().unwrap().channels }
+    }
+
+    fn best_effort_timestamp(&self) -> Option<i64> {
+        let frame = unsafe { self.inner.as_ref().unwrap() };
+
+        [frame.best_effort_timestamp, frame.pts]
+            .iter()
+            .copied()
+            .find(|&pts| pts != AV_NOPTS_VALUE)
     }
 
     fn extended_data(&self) -> *mut *const u8 {
         unsafe { self.inner.as_ref().unwrap().extended_data as *mut *const u8 }
     }
-}
-
-struct Stream {
-    inner: *mut AVStream,
-    index: i32,
-}
 
-impl Stream {
-    fn new(inner: *mut AVStream, index: i32) -> Stream {
-        Stream { inner, index }
+    /// Format of the decoded samples, which may differ from the codec context after a change
+    /// mid-stream
+    #[cfg(feature = "filter")]
+    fn audio_format(&self) -> AudioFormat {
+        let frame = unsafe { self.inner.as_ref().unwrap() };
+
+        AudioFormat::new(
+            frame.channel_layout,
+            frame.channels,
+            unsafe { std::mem::transmute::<i32, AVSampleFormat>(frame.format) },
+            frame.sample_rate,
+        )
     }
 
-    fn get_codec(&self) -> Result<Codec, Error> {
-        // Get streams codec
-        let codec_params = unsafe { self.inner.as_ref().unwrap().codecpar };
-        let codec_id = unsafe { codec_params.as_ref().unwrap().codec_id };
+    /// Replaces the frame with `num_samples` of silence in `format`, without a timestamp
+    #[cfg(feature = "filter")]
+    fn set_silence(&mut self, format: AudioFormat, num_samples: i32) -> Result<(), Error> {
+        unsafe { av_frame_unref(self.inner) };
+
+        {
+            let frame = unsafe { self.inner.as_mut().unwrap() };
+            frame.format = format.sample_format as i32;
+            frame.channel_layout = format.channel_layout;
+            frame.channels = format.channels();
+            frame.sample_rate = format.sample_rate;
+            frame.nb_samples = num_samples;
+        }
 
-        let codec: *mut AVCodec = unsafe { avcodec_find_decoder(codec_id) };
-        if codec.is_null() {
-            return Err(Error::NullCodec);
+        let status = unsafe { av_frame_get_buffer(self.inner, 0) };
+        if status < 0 {
+            return Err(Error::AllocateFrame(AvError::new(status)));
         }
 
-        Ok(Codec::new(codec))
-    }
+        unsafe {
+            av_samples_set_silence(
+                self.inner.as_ref().unwrap().extended_data,
+                0,
+                num_samples,
+                format.channels(),
+                format.sample_format,
+            )
+        };
 
-    #[allow(dead_code)]
-    fn duration(&self) -> i64 {
-        unsafe { self.inner.as_ref().unwrap().duration }
+        Ok(())
     }
 }
 
@@ -475,12 +1406,15 @@ impl CodecContext {
     }
 
     fn copy_parameters_from_stream(&self, stream: &Stream) -> Result<(), Error> {
-        let params = unsafe { stream.inner.as_ref().unwrap().codecpar };
+        let params = stream.codec_parameters();
 
         let status = unsafe { avcodec_parameters_to_context(self.inner, params) };
 
         if status != 0 {
-            return Err(Error::CodecParamsToContext);
+            return Err(Error::CodecParamsToContext {
+                stream_index: stream.index,
+                source: AvError::new(status),
+            });
         }
 
         Ok(())
@@ -495,11 +1429,21 @@ impl CodecContext {
         }
     }
 
-    fn initialize(&self) -> Result<(), Error> {
+    /// Have the decoder keep going on damaged data rather than failing, when tolerated by the policy
+    fn apply_error_policy(&self, error_policy: ErrorPolicy) {
+        if error_policy != ErrorPolicy::Strict {
+            unsafe { self.inner.as_mut().unwrap().err_recognition = AV_EF_IGNORE_ERR };
+        }
+    }
+
+    fn initialize(&self, stream: &Stream) -> Result<(), Error> {
         let status = unsafe { avcodec_open2(self.inner, self.codec, &mut std::ptr::null_mut()) };
 
         if status != 0 {
-            return Err(Error::InitializeDecoder);
+            return Err(Error::InitializeDecoder {
+                stream_index: stream.index,
+                source: AvError::new(status),
+            });
         }
 
         Ok(())
@@ -525,6 +1469,10 @@ impl CodecContext {
         unsafe { self.inner.as_ref().unwrap().sample_rate }
     }
 
+    fn frame_size(&self) -> i32 {
+        unsafe { self.inner.as_ref().unwrap().frame_size }
+    }
+
     fn sample_size(&self) -> i32 {
         unsafe { av_get_bytes_per_sample(self.inner.as_ref().unwrap().sample_fmt) }
     }
@@ -537,6 +1485,15 @@ impl CodecContext {
         unsafe { self.inner.as_ref().unwrap().channel_layout }
     }
 
+    fn audio_format(&self) -> AudioFormat {
+        AudioFormat::new(
+            self.channel_layout(),
+            self.channels(),
+            self.sample_format(),
+            self.sample_rate(),
+        )
+    }
+
     fn is_planar(&self) -> i32 {
         unsafe { av_sample_fmt_is_planar(self.inner.as_ref().unwrap().sample_fmt) }
     }
@@ -544,30 +1501,44 @@ impl CodecContext {
 
 struct Codec {
     inner: *mut AVCodec,
+    stream_index: i32,
 }
 
 impl Codec {
-    fn new(inner: *mut AVCodec) -> Codec {
-        Codec { inner }
+    fn new(inner: *mut AVCodec, stream_index: i32) -> Codec {
+        Codec {
+            inner,
+            stream_index,
+        }
+    }
+
+    fn find_decoder(stream: &Stream) -> Result<Codec, Error> {
+        // Get streams codec
+        let codec_id = unsafe { stream.codec_parameters().as_ref().unwrap().codec_id };
+
+        let codec: *mut AVCodec = unsafe { avcodec_find_decoder(codec_id) };
+        if codec.is_null() {
+            return Err(Error::NullCodec {
+                stream_index: stream.index,
+            });
+        }
+
+        Ok(Codec::new(codec, stream.index))
     }
 
     fn get_context(&self) -> Result<CodecContext, Error> {
         let ctx: *mut AVCodecContext = unsafe { avcodec_alloc_context3(self.inner) };
 
         if ctx.is_null() {
-            return Err(Error::NullCodecContext);
+            return Err(Error::NullCodecContext {
+                stream_index: self.stream_index,
+            });
         }
 
         Ok(CodecContext::new(ctx, self.inner))
     }
 }
 
-enum ReadFrameStatus {
-    Ok,
-    Eof,
-    Other(i32),
-}
-
 enum SendPacketStatus {
     Ok,
     Other(i32),
@@ -577,30 +1548,11 @@ enum ReceiveFrameStatus {
     Ok,
     Again,
     Deadlk,
+    /// Fully drained at the end of the stream
+    Eof,
     Other(i32),
 }
 
-fn find_audio_stream(streams: &[*mut AVStream]) -> Result<i32, Error> {
-    for stream in streams {
-        let codec_type = unsafe {
-            stream
-                .as_ref()
-                .unwrap()
-                .codecpar
-                .as_ref()
-                .unwrap()
-                .codec_type
-        };
-        let index = unsafe { stream.as_ref().unwrap().index };
-
-        if codec_type == AVMediaType::AVMEDIA_TYPE_AUDIO {
-            return Ok(index);
-        }
-    }
-
-    Err(Error::NoAudioStream)
-}
-
 fn print_codec_info(codec_ctx: &CodecContext) {
     info!("Codec:         {}", codec_ctx.codec_name());
     info!("Sample Format: {}", codec_ctx.sample_format_name());
@@ -614,7 +1566,7 @@ fn drain_decoder(codec_ctx: *mut AVCodecContext) -> Result<(), Error> {
     let status = unsafe { avcodec_send_packet(codec_ctx, std::ptr::null()) };
     if status == 0 {
     } else {
-        return Err(Error::DrainDecoder(status));
+        return Err(Error::DrainDecoder(AvError::new(status)));
     }
 
     Ok(())
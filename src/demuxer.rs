@@ -0,0 +1,369 @@
+use crate::error::{AvError, Error};
+use crate::format::{FormatContext, Packet, ReadFrameStatus, Stream};
+use crate::frame::Position;
+use crate::stream_selector::StreamSelector;
+
+use ffmpeg_sys_next::{
+    av_get_audio_frame_duration2, av_packet_get_side_data, av_register_all, av_rescale_q,
+    avcodec_get_name, AVPacketSideDataType, AVRational, AV_NOPTS_VALUE, AV_PKT_FLAG_CORRUPT,
+    AV_PKT_FLAG_KEY,
+};
+use std::ffi::CStr;
+use std::path::Path;
+use std::slice;
+use std::time::Duration;
+
+const MICROSECONDS: AVRational = AVRational {
+    num: 1,
+    den: 1_000_000,
+};
+
+/// Reads compressed packets of a single stream without decoding them
+///
+/// Useful to remux or forward audio untouched. Packets are returned in the order they are
+/// stored in the container, along with the parameters needed to set up a decoder for them.
+pub struct Demuxer {
+    format_ctx: FormatContext,
+    stream: Stream,
+    packet: Packet,
+    finished: bool,
+}
+
+impl Demuxer {
+    /// Open the first audio stream of the file
+    pub fn open(path: impl AsRef<Path>) -> Result<Demuxer, Error> {
+        let format_ctx = open_format_context(path.as_ref())?;
+        let stream = format_ctx.get_audio_stream()?;
+
+        Ok(Demuxer::new(format_ctx, stream))
+    }
+
+    /// Open the audio stream picked by `selector`
+    pub fn open_selected(
+        path: impl AsRef<Path>,
+        selector: &StreamSelector,
+    ) -> Result<Demuxer, Error> {
+        let format_ctx = open_format_context(path.as_ref())?;
+        let stream = format_ctx.select_audio_stream(selector)?;
+
+        Ok(Demuxer::new(format_ctx, stream))
+    }
+
+    /// Open the stream at `stream_index`, which may be of any media type
+    pub fn open_stream(path: impl AsRef<Path>, stream_index: usize) -> Result<Demuxer, Error> {
+        let format_ctx = open_format_context(path.as_ref())?;
+        let stream = format_ctx.get_stream(stream_index)?;
+
+        Ok(Demuxer::new(format_ctx, stream))
+    }
+
+    fn new(format_ctx: FormatContext, stream: Stream) -> Demuxer {
+        Demuxer {
+            format_ctx,
+            stream,
+            packet: Packet::new(),
+            finished: false,
+        }
+    }
+
+    pub fn stream_index(&self) -> usize {
+        self.stream.index as usize
+    }
+
+    /// Unit of the packet timestamps, as `(numerator, denominator)` of a second
+    pub fn time_base(&self) -> (i32, i32) {
+        let time_base = self.stream.time_base();
+
+        (time_base.num, time_base.den)
+    }
+
+    pub fn codec_parameters(&self) -> CodecParameters {
+        let params = unsafe { self.stream.codec_parameters().as_ref().unwrap() };
+
+        let codec_name = unsafe { CStr::from_ptr(avcodec_get_name(params.codec_id)) };
+
+        let extradata = if params.extradata.is_null() || params.extradata_size <= 0 {
+            vec![]
+        } else {
+            unsafe { slice::from_raw_parts(params.extradata, params.extradata_size as usize) }
+                .to_vec()
+        };
+
+        CodecParameters {
+            codec_name: codec_name.to_string_lossy().into_owned(),
+            bit_rate: params.bit_rate,
+            sample_rate: params.sample_rate.max(0) as u32,
+            channels: params.channels.max(0) as u16,
+            channel_layout: params.channel_layout,
+            frame_size: params.frame_size.max(0) as u32,
+            initial_padding: params.initial_padding.max(0) as u32,
+            extradata,
+        }
+    }
+
+    /// Count the samples per channel in the remaining packets of the stream, without decoding
+    /// them. Samples the decoder drops as encoder delay or padding aren't counted.
+    pub(crate) fn scan_samples(&mut self) -> Result<u64, Error> {
+        let mut samples = 0i64;
+
+        self.scan_packets(|packet| samples += packet.samples - packet.skipped)?;
+
+        Ok(samples.max(0) as u64)
+    }
+
+    /// Read the remaining packets of the stream without copying their data, passing a summary
+    /// of each to `f`
+    pub(crate) fn scan_packets(&mut self, mut f: impl FnMut(ScannedPacket)) -> Result<(), Error> {
+        let params = self.stream.codec_parameters();
+        let time_base = self.stream.time_base();
+        let start_time = timestamp(self.stream.start_time()).unwrap_or(0);
+        let sample_rate = AVRational {
+            num: 1,
+            den: unsafe { (*params).sample_rate }.max(1),
+        };
+
+        while !self.finished {
+            match self.format_ctx.read_packet(&mut self.packet) {
+                ReadFrameStatus::Ok => {}
+                ReadFrameStatus::Eof => {
+                    self.finished = true;
+                    break;
+                }
+                ReadFrameStatus::Other(status) => {
+                    self.finished = true;
+                    return Err(Error::ReadFrame(AvError::new(status)));
+                }
+            }
+
+            if !self.packet_for_stream() {
+                self.packet.reset();
+                continue;
+            }
+
+            let packet = self.packet.get();
+
+            // Demuxers set exact durations for codecs with variable frame sizes, otherwise
+            // the size follows from the codec and packet size
+            let samples = if packet.duration > 0 {
+                unsafe { av_rescale_q(packet.duration, time_base, sample_rate) }
+            } else {
+                i64::from(unsafe { av_get_audio_frame_duration2(params, packet.size) })
+            };
+
+            f(ScannedPacket {
+                sample_position: timestamp(packet.pts)
+                    .map(|pts| unsafe { av_rescale_q(pts - start_time, time_base, sample_rate) }),
+                position: if packet.pos >= 0 {
+                    Some(packet.pos as u64)
+                } else {
+                    None
+                },
+                samples,
+                skipped: self.skip_samples(),
+            });
+
+            self.packet.reset();
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn sample_rate(&self) -> u32 {
+        self.stream.sample_rate()
+    }
+
+    /// Samples at the start and end of the current packet the decoder drops
+    fn skip_samples(&self) -> i64 {
+        let mut size = 0;
+        let data = unsafe {
+            av_packet_get_side_data(
+                self.packet.inner.as_ptr(),
+                AVPacketSideDataType::AV_PKT_DATA_SKIP_SAMPLES,
+                &mut size,
+            )
+        };
+
+        if data.is_null() || size < 8 {
+            return 0;
+        }
+
+        let data = unsafe { slice::from_raw_parts(data, 8) };
+        let start = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        let end = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+
+        i64::from(start) + i64::from(end)
+    }
+
+    fn packet_for_stream(&self) -> bool {
+        self.packet.stream_index() == self.stream.index
+    }
+
+    fn take_packet(&mut self) -> EncodedPacket {
+        let packet = self.packet.get();
+        let time_base = self.stream.time_base();
+
+        let data = if packet.data.is_null() || packet.size <= 0 {
+            vec![]
+        } else {
+            unsafe { slice::from_raw_parts(packet.data, packet.size as usize) }.to_vec()
+        };
+
+        let pts = timestamp(packet.pts);
+        let start_time = timestamp(self.stream.start_time()).unwrap_or(0);
+        let time = pts.map(|pts| rescale_to_duration(pts - start_time, time_base));
+
+        let encoded_packet = EncodedPacket {
+            data,
+            pts,
+            dts: timestamp(packet.dts),
+            duration: packet.duration,
+            time,
+            flags: packet.flags,
+            position: if packet.pos >= 0 {
+                Some(packet.pos as u64)
+            } else {
+                None
+            },
+        };
+
+        self.packet.reset();
+
+        encoded_packet
+    }
+}
+
+unsafe impl Send for Demuxer {}
+
+impl Iterator for Demuxer {
+    type Item = Result<EncodedPacket, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.finished {
+            match self.format_ctx.read_packet(&mut self.packet) {
+                ReadFrameStatus::Ok => {}
+                ReadFrameStatus::Eof => self.finished = true,
+                ReadFrameStatus::Other(status) => {
+                    self.finished = true;
+                    return Some(Err(Error::ReadFrame(AvError::new(status))));
+                }
+            }
+
+            if self.finished {
+                break;
+            }
+
+            if !self.packet_for_stream() {
+                self.packet.reset();
+                continue;
+            }
+
+            return Some(Ok(self.take_packet()));
+        }
+
+        None
+    }
+}
+
+/// Exact length of the first audio stream of the file, counted from its packets
+///
+/// Reads the whole file, but doesn't decode it. Use this where the container only estimates
+/// the duration, e.g. VBR MP3 without a Xing header, raw AAC or some Ogg files.
+pub fn scan_duration(path: impl AsRef<Path>) -> Result<Position, Error> {
+    scan_samples_of(Demuxer::open(path)?)
+}
+
+/// Exact length of the stream at `stream_index`, see `scan_duration`
+pub(crate) fn scan_stream_duration(
+    path: impl AsRef<Path>,
+    stream_index: usize,
+) -> Result<Position, Error> {
+    scan_samples_of(Demuxer::open_stream(path, stream_index)?)
+}
+
+fn scan_samples_of(mut demuxer: Demuxer) -> Result<Position, Error> {
+    let samples = demuxer.scan_samples()?;
+
+    Ok(Position::new(samples, demuxer.sample_rate()))
+}
+
+/// Parameters of the stream a [`Demuxer`](struct.Demuxer.html) reads from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodecParameters {
+    /// Short codec name, e.g. `opus` or `aac`
+    pub codec_name: String,
+    pub bit_rate: i64,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub channel_layout: u64,
+    /// Samples per channel in each packet, 0 if variable or unknown
+    pub frame_size: u32,
+    /// Samples per channel of encoder delay to drop at the start of the stream
+    pub initial_padding: u32,
+    /// Codec specific setup data, e.g. the `OpusHead` or AAC `AudioSpecificConfig`
+    pub extradata: Vec<u8>,
+}
+
+/// Summary of a packet read by `Demuxer::scan_packets`
+pub(crate) struct ScannedPacket {
+    /// Timestamp in samples per channel since the start of the stream, may be negative for
+    /// encoder delay
+    pub(crate) sample_position: Option<i64>,
+    /// Byte offset in the file
+    pub(crate) position: Option<u64>,
+    /// Samples per channel the packet decodes to
+    pub(crate) samples: i64,
+    /// Of these, samples the decoder drops as encoder delay or padding
+    pub(crate) skipped: i64,
+}
+
+/// A compressed packet as stored in the container
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedPacket {
+    pub data: Vec<u8>,
+    /// Presentation timestamp, in units of the streams [`time_base`](struct.Demuxer.html#method.time_base)
+    pub pts: Option<i64>,
+    /// Decoding timestamp, in units of the streams [`time_base`](struct.Demuxer.html#method.time_base)
+    pub dts: Option<i64>,
+    /// Duration in units of the streams [`time_base`](struct.Demuxer.html#method.time_base), 0 if unknown
+    pub duration: i64,
+    /// Presentation time since the start of the stream, like the timestamps of decoded frames.
+    /// Packets presented before the start are clamped to zero.
+    pub time: Option<Duration>,
+    /// Raw `AV_PKT_FLAG_*` flags
+    pub flags: i32,
+    /// Byte offset of the packet in the file, if known
+    pub position: Option<u64>,
+}
+
+impl EncodedPacket {
+    pub fn is_key(&self) -> bool {
+        self.flags & AV_PKT_FLAG_KEY != 0
+    }
+
+    pub fn is_corrupt(&self) -> bool {
+        self.flags & AV_PKT_FLAG_CORRUPT != 0
+    }
+}
+
+fn open_format_context(path: &Path) -> Result<FormatContext, Error> {
+    unsafe { av_register_all() };
+
+    let format_ctx = FormatContext::open(&path.display().to_string())?;
+    format_ctx.find_stream_info()?;
+
+    Ok(format_ctx)
+}
+
+fn timestamp(ts: i64) -> Option<i64> {
+    if ts == AV_NOPTS_VALUE {
+        None
+    } else {
+        Some(ts)
+    }
+}
+
+fn rescale_to_duration(ts: i64, time_base: AVRational) -> Duration {
+    let micros = unsafe { av_rescale_q(ts, time_base, MICROSECONDS) };
+
+    Duration::from_micros(micros.max(0) as u64)
+}
@@ -0,0 +1,852 @@
+use crate::error::{AvError, Error, AVERROR_EAGAIN, AVERROR_EOF};
+use crate::format::Packet;
+use crate::metadata::{CoverArt, Metadata};
+use crate::resample::{AudioFormat, SwrContext};
+
+use ffmpeg_sys_next::{
+    av_frame_alloc, av_frame_free, av_frame_get_buffer, av_frame_make_writable,
+    av_get_bytes_per_sample, av_get_sample_fmt_name, av_interleaved_write_frame, av_new_packet,
+    av_packet_rescale_ts, av_register_all, av_sample_fmt_is_planar, av_samples_set_silence,
+    av_write_trailer, avcodec_alloc_context3, avcodec_find_encoder, avcodec_find_encoder_by_name,
+    avcodec_free_context, avcodec_open2, avcodec_parameters_from_context, avcodec_receive_packet,
+    avcodec_send_frame, avformat_alloc_output_context2, avformat_free_context, avformat_new_stream,
+    avformat_write_header, avio_closep, avio_open, swr_convert, swr_get_out_samples, AVCodec,
+    AVCodecContext, AVCodecID, AVFormatContext, AVFrame, AVMediaType, AVRational, AVSampleFormat,
+    AVStream, AVFMT_GLOBALHEADER, AVFMT_NOFILE, AVIO_FLAG_WRITE, AV_CODEC_CAP_SMALL_LAST_FRAME,
+    AV_CODEC_CAP_VARIABLE_FRAME_SIZE, AV_CODEC_FLAG_GLOBAL_HEADER, AV_DISPOSITION_ATTACHED_PIC,
+    AV_PKT_FLAG_KEY, FF_COMPLIANCE_EXPERIMENTAL,
+};
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use log::{error, info};
+
+const INPUT_FORMAT: AVSampleFormat = AVSampleFormat::AV_SAMPLE_FMT_S16;
+/// Containers that can embed a picture, by muxer name
+const COVER_ART_CONTAINERS: &[&str] = &["mp3", "mp4", "ipod", "mov", "flac"];
+/// Samples per channel in each frame, for codecs that accept any frame size (e.g. PCM)
+const DEFAULT_FRAME_SIZE: i32 = 1024;
+
+/// Codecs an [`Encoder`](struct.Encoder.html) can be asked for explicitly
+///
+/// Each is mapped to the best encoder ffmpeg was built with, e.g. `libopus` before the native
+/// `opus` encoder. Encoders that aren't available result in
+/// [`Error::EncoderNotFound`](enum.Error.html#variant.EncoderNotFound).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    Flac,
+    Opus,
+    Aac,
+    Mp3,
+    /// Signed 16bit little endian PCM, as stored in `.wav` files
+    Pcm,
+}
+
+impl AudioCodec {
+    fn encoder_names(self) -> &'static [&'static str] {
+        match self {
+            AudioCodec::Flac => &["flac"],
+            AudioCodec::Opus => &["libopus", "opus"],
+            AudioCodec::Aac => &["libfdk_aac", "aac"],
+            AudioCodec::Mp3 => &["libmp3lame", "libshine"],
+            AudioCodec::Pcm => &["pcm_s16le"],
+        }
+    }
+}
+
+/// Options to open an [`Encoder`](struct.Encoder.html) with, created by [`Encoder::builder`](struct.Encoder.html#method.builder)
+#[derive(Debug, Clone)]
+pub struct EncoderBuilder {
+    path: PathBuf,
+    channels: u16,
+    sample_rate: u32,
+    codec: Option<AudioCodec>,
+    container: Option<String>,
+    bit_rate: Option<u64>,
+    output_channels: Option<u16>,
+    output_sample_rate: Option<u32>,
+    metadata: Metadata,
+    cover_art: Option<CoverArt>,
+}
+
+impl EncoderBuilder {
+    /// Codec to encode with, defaults to the default audio codec of the container
+    pub fn codec(mut self, codec: AudioCodec) -> EncoderBuilder {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Short name of the container format, e.g. `ogg`, `matroska` or `wav`. Defaults to
+    /// guessing the container from the file extension.
+    pub fn container(mut self, container: impl Into<String>) -> EncoderBuilder {
+        self.container = Some(container.into());
+        self
+    }
+
+    /// Target bit rate in bits per second, ignored by lossless codecs
+    pub fn bit_rate(mut self, bit_rate: u64) -> EncoderBuilder {
+        self.bit_rate = Some(bit_rate);
+        self
+    }
+
+    /// Number of channels to encode, remixing the written samples. Defaults to the input
+    /// channels if the codec supports them.
+    pub fn output_channels(mut self, channels: u16) -> EncoderBuilder {
+        self.output_channels = Some(channels);
+        self
+    }
+
+    /// Sample rate to encode at, resampling the written samples. Defaults to the input rate if
+    /// the codec supports it.
+    pub fn output_sample_rate(mut self, sample_rate: u32) -> EncoderBuilder {
+        self.output_sample_rate = Some(sample_rate);
+        self
+    }
+
+    /// Tags to write to the file
+    pub fn metadata(mut self, metadata: Metadata) -> EncoderBuilder {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Picture to embed in the file. Only mp3, mp4 and flac files can hold one, it's skipped for
+    /// other containers.
+    pub fn cover_art(mut self, cover_art: CoverArt) -> EncoderBuilder {
+        self.cover_art = Some(cover_art);
+        self
+    }
+
+    pub fn open(self) -> Result<Encoder, Error> {
+        Encoder::open_with(self)
+    }
+}
+
+/// Encodes interleaved signed 16bit samples, as produced by the [`Decoder`](struct.Decoder.html),
+/// and writes them to a file
+///
+/// Samples are converted to whatever sample format, rate and channel layout the codec requires.
+/// Call [`finish`](#method.finish) once all samples are written to flush the encoder and write
+/// the container trailer, otherwise this happens on drop and errors are only logged. Call
+/// [`abort`](#method.abort) instead to discard a file that can't be completed.
+pub struct Encoder {
+    output_ctx: OutputContext,
+    codec_ctx: EncoderContext,
+    stream: *mut AVStream,
+    swr_ctx: SwrContext,
+    frame: EncoderFrame,
+    packet: Packet,
+    input: AudioFormat,
+    output: AudioFormat,
+    frame_size: i32,
+    frame_filled: i32,
+    small_last_frame: bool,
+    next_pts: i64,
+    finished: bool,
+}
+
+impl Encoder {
+    pub fn create(
+        path: impl AsRef<Path>,
+        channels: u16,
+        sample_rate: u32,
+    ) -> Result<Encoder, Error> {
+        Encoder::builder(path, channels, sample_rate).open()
+    }
+
+    /// `channels` and `sample_rate` describe the samples that will be written
+    pub fn builder(path: impl AsRef<Path>, channels: u16, sample_rate: u32) -> EncoderBuilder {
+        EncoderBuilder {
+            path: path.as_ref().to_owned(),
+            channels,
+            sample_rate,
+            codec: None,
+            container: None,
+            bit_rate: None,
+            output_channels: None,
+            output_sample_rate: None,
+            metadata: Metadata::default(),
+            cover_art: None,
+        }
+    }
+
+    fn open_with(builder: EncoderBuilder) -> Result<Encoder, Error> {
+        unsafe { av_register_all() };
+
+        let path = builder.path.display().to_string();
+
+        // Allocate the output context, guessing the container if not specified
+        let mut output_ctx = OutputContext::new(&path, builder.container.as_deref())?;
+
+        let codec = find_encoder(builder.codec, output_ctx.default_audio_codec())?;
+        let codec_ref = unsafe { codec.as_ref().unwrap() };
+        let codec_name = unsafe { CStr::from_ptr(codec_ref.name) }
+            .to_string_lossy()
+            .into_owned();
+
+        let input = AudioFormat::new(
+            0,
+            builder.channels as i32,
+            INPUT_FORMAT,
+            builder.sample_rate as i32,
+        );
+        let wanted = AudioFormat::new(
+            0,
+            builder.output_channels.unwrap_or(builder.channels) as i32,
+            INPUT_FORMAT,
+            builder.output_sample_rate.unwrap_or(builder.sample_rate) as i32,
+        );
+        let output = AudioFormat {
+            channel_layout: select_channel_layout(codec_ref, wanted.channel_layout),
+            sample_format: select_sample_format(codec_ref),
+            sample_rate: select_sample_rate(codec_ref, wanted.sample_rate),
+        };
+
+        // Setup codec context and initialize
+        let codec_ctx = EncoderContext::new(codec, &codec_name)?;
+        unsafe {
+            let ctx = codec_ctx.inner.as_mut().unwrap();
+
+            ctx.sample_fmt = output.sample_format;
+            ctx.sample_rate = output.sample_rate;
+            ctx.channel_layout = output.channel_layout;
+            ctx.channels = output.channels();
+            ctx.time_base = AVRational {
+                num: 1,
+                den: output.sample_rate,
+            };
+            if let Some(bit_rate) = builder.bit_rate {
+                ctx.bit_rate = bit_rate as i64;
+            }
+            // Allows the native opus encoder to be used
+            ctx.strict_std_compliance = FF_COMPLIANCE_EXPERIMENTAL;
+
+            if output_ctx.flags() & AVFMT_GLOBALHEADER != 0 {
+                ctx.flags |= AV_CODEC_FLAG_GLOBAL_HEADER as i32;
+            }
+        }
+
+        let status = unsafe { avcodec_open2(codec_ctx.inner, codec, &mut ptr::null_mut()) };
+        if status != 0 {
+            return Err(Error::InitializeEncoder {
+                codec: codec_name,
+                source: AvError::new(status),
+            });
+        }
+
+        // Add the streams, tags and write the container header
+        let stream = output_ctx.new_stream(&codec_ctx)?;
+        let cover_art = match builder.cover_art {
+            Some(cover_art) => output_ctx
+                .new_cover_art_stream(&cover_art)?
+                .map(|stream| (stream, cover_art)),
+            None => None,
+        };
+        output_ctx.set_metadata(stream, &builder.metadata);
+
+        output_ctx.open_io()?;
+        output_ctx.write_header()?;
+
+        // Muxers expect the picture before any audio
+        if let Some((cover_art_stream, cover_art)) = cover_art {
+            output_ctx.write_cover_art(cover_art_stream, &cover_art)?;
+        }
+
+        let (frame_size, small_last_frame) = frame_size(codec_ref, &codec_ctx);
+        let frame = EncoderFrame::new(output, frame_size)?;
+
+        let swr_ctx = SwrContext::new(input, output)?;
+
+        print_encoder_info(&codec_name, output, &path);
+
+        Ok(Encoder {
+            output_ctx,
+            codec_ctx,
+            stream,
+            swr_ctx,
+            frame,
+            packet: Packet::new(),
+            input,
+            output,
+            frame_size,
+            frame_filled: 0,
+            small_last_frame,
+            next_pts: 0,
+            finished: false,
+        })
+    }
+
+    /// Write interleaved samples. The length must be a multiple of the number of channels.
+    pub fn write(&mut self, samples: &[i16]) -> Result<(), Error> {
+        let num_samples = samples.len() / self.input.channels().max(1) as usize;
+        if num_samples == 0 {
+            return Ok(());
+        }
+
+        // Buffer the samples in the resampler, they're pulled out a frame at a time
+        let mut input = [samples.as_ptr() as *const u8];
+        let status = unsafe {
+            swr_convert(
+                self.swr_ctx.inner,
+                ptr::null_mut(),
+                0,
+                input.as_mut_ptr(),
+                num_samples as i32,
+            )
+        };
+        if status < 0 {
+            return Err(Error::ConvertSamples(AvError::new(status)));
+        }
+
+        self.encode_converted(false)
+    }
+
+    /// Flush all buffered samples and finalize the file
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.flush()
+    }
+
+    /// Close the file without flushing or writing the trailer and remove it, so a file that's cut
+    /// short doesn't look complete
+    pub fn abort(mut self) -> Result<(), Error> {
+        self.finished = true;
+        let path = self.output_ctx.path.clone();
+
+        drop(self);
+        fs::remove_file(path)?;
+
+        Ok(())
+    }
+
+    /// Fills frames from the resampler and encodes them. Unless flushing, stops once there aren't
+    /// enough samples buffered for another full frame.
+    fn encode_converted(&mut self, flush: bool) -> Result<(), Error> {
+        loop {
+            let wanted = self.frame_size - self.frame_filled;
+
+            let available = unsafe { swr_get_out_samples(self.swr_ctx.inner, 0) };
+            if !flush && available < wanted {
+                return Ok(());
+            }
+
+            if self.frame_filled == 0 {
+                // The encoder may still hold a reference to the previous frame
+                let status = unsafe { av_frame_make_writable(self.frame.inner) };
+                if status < 0 {
+                    return Err(Error::AllocateFrame(AvError::new(status)));
+                }
+            }
+
+            let mut planes = self.frame_planes(self.frame_filled);
+            let converted = unsafe {
+                swr_convert(
+                    self.swr_ctx.inner,
+                    planes.as_mut_ptr(),
+                    wanted,
+                    ptr::null_mut(),
+                    0,
+                )
+            };
+            if converted < 0 {
+                return Err(Error::ConvertSamples(AvError::new(converted)));
+            }
+
+            self.frame_filled += converted;
+
+            if self.frame_filled == self.frame_size {
+                self.encode_frame()?;
+            } else if converted == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Pointers to each plane of the frame, `offset` samples in
+    fn frame_planes(&self, offset: i32) -> Vec<*mut u8> {
+        let extended_data = unsafe { self.frame.inner.as_ref().unwrap().extended_data };
+
+        let sample_size = unsafe { av_get_bytes_per_sample(self.output.sample_format) } as usize;
+        let channels = self.output.channels() as usize;
+
+        let (num_planes, stride) =
+            if unsafe { av_sample_fmt_is_planar(self.output.sample_format) } != 0 {
+                (channels, sample_size)
+            } else {
+                (1, sample_size * channels)
+            };
+
+        (0..num_planes)
+            .map(|plane| unsafe { (*extended_data.add(plane)).add(offset as usize * stride) })
+            .collect()
+    }
+
+    fn encode_frame(&mut self) -> Result<(), Error> {
+        unsafe {
+            let frame = self.frame.inner.as_mut().unwrap();
+
+            frame.nb_samples = self.frame_filled;
+            frame.pts = self.next_pts;
+        }
+
+        self.next_pts += self.frame_filled as i64;
+        self.frame_filled = 0;
+
+        let status = unsafe { avcodec_send_frame(self.codec_ctx.inner, self.frame.inner) };
+        if status < 0 {
+            return Err(Error::SendFrame(AvError::new(status)));
+        }
+
+        self.write_packets()
+    }
+
+    /// Write all packets the encoder has ready
+    fn write_packets(&mut self) -> Result<(), Error> {
+        loop {
+            let packet = self.packet.inner.as_mut_ptr();
+
+            let status = unsafe { avcodec_receive_packet(self.codec_ctx.inner, packet) };
+            match status {
+                AVERROR_EAGAIN | AVERROR_EOF => return Ok(()),
+                _ if status < 0 => return Err(Error::ReceivePacket(AvError::new(status))),
+                _ => {}
+            }
+
+            unsafe {
+                let stream = self.stream.as_ref().unwrap();
+
+                av_packet_rescale_ts(
+                    packet,
+                    self.codec_ctx.inner.as_ref().unwrap().time_base,
+                    stream.time_base,
+                );
+                packet.as_mut().unwrap().stream_index = stream.index;
+            }
+
+            // Takes ownership of the packets data
+            let status = unsafe { av_interleaved_write_frame(self.output_ctx.inner, packet) };
+            if status < 0 {
+                return Err(Error::WritePacket(AvError::new(status)));
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.finished = true;
+
+        self.encode_converted(true)?;
+
+        if self.frame_filled > 0 {
+            if !self.small_last_frame {
+                // Pad the last frame with silence
+                unsafe {
+                    av_samples_set_silence(
+                        self.frame.inner.as_ref().unwrap().extended_data,
+                        self.frame_filled,
+                        self.frame_size - self.frame_filled,
+                        self.output.channels(),
+                        self.output.sample_format,
+                    )
+                };
+                self.frame_filled = self.frame_size;
+            }
+
+            self.encode_frame()?;
+        }
+
+        // Drain the encoder
+        let status = unsafe { avcodec_send_frame(self.codec_ctx.inner, ptr::null()) };
+        if status < 0 {
+            return Err(Error::SendFrame(AvError::new(status)));
+        }
+        self.write_packets()?;
+
+        let status = unsafe { av_write_trailer(self.output_ctx.inner) };
+        if status < 0 {
+            return Err(Error::WriteTrailer(AvError::new(status)));
+        }
+
+        Ok(())
+    }
+}
+
+unsafe impl Send for Encoder {}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self.flush() {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+struct OutputContext {
+    inner: *mut AVFormatContext,
+    path: String,
+    io_opened: bool,
+}
+
+impl OutputContext {
+    fn new(path: &str, container: Option<&str>) -> Result<OutputContext, Error> {
+        let mut inner = ptr::null_mut::<AVFormatContext>();
+
+        let c_path = CString::new(path).unwrap();
+        let container = container.map(|container| CString::new(container).unwrap());
+
+        let status = unsafe {
+            avformat_alloc_output_context2(
+                &mut inner,
+                ptr::null_mut(),
+                container.as_ref().map_or(ptr::null(), |name| name.as_ptr()),
+                c_path.as_ptr(),
+            )
+        };
+        if status < 0 || inner.is_null() {
+            return Err(Error::InitializeOutputContext {
+                path: path.to_owned(),
+                source: AvError::new(status),
+            });
+        }
+
+        Ok(OutputContext {
+            inner,
+            path: path.to_owned(),
+            io_opened: false,
+        })
+    }
+
+    fn flags(&self) -> i32 {
+        unsafe { self.inner.as_ref().unwrap().oformat.as_ref().unwrap().flags }
+    }
+
+    fn name(&self) -> String {
+        let name = unsafe { self.inner.as_ref().unwrap().oformat.as_ref().unwrap().name };
+
+        unsafe { CStr::from_ptr(name) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn default_audio_codec(&self) -> AVCodecID {
+        unsafe {
+            self.inner
+                .as_ref()
+                .unwrap()
+                .oformat
+                .as_ref()
+                .unwrap()
+                .audio_codec
+        }
+    }
+
+    fn new_stream(&self, codec_ctx: &EncoderContext) -> Result<*mut AVStream, Error> {
+        let stream = unsafe { avformat_new_stream(self.inner, ptr::null()) };
+        if stream.is_null() {
+            return Err(Error::NewStream {
+                path: self.path.clone(),
+            });
+        }
+
+        let status = unsafe {
+            avcodec_parameters_from_context(stream.as_ref().unwrap().codecpar, codec_ctx.inner)
+        };
+        if status < 0 {
+            return Err(Error::CodecParamsFromContext(AvError::new(status)));
+        }
+
+        unsafe { stream.as_mut().unwrap().time_base = codec_ctx.inner.as_ref().unwrap().time_base };
+
+        Ok(stream)
+    }
+
+    /// Add a stream for an attached picture, `None` if the container can't hold one
+    fn new_cover_art_stream(&self, cover_art: &CoverArt) -> Result<Option<*mut AVStream>, Error> {
+        let name = self.name();
+        if !COVER_ART_CONTAINERS.contains(&name.as_str()) {
+            info!("Skipping cover art, not supported by {}", name);
+            return Ok(None);
+        }
+
+        let stream = unsafe { avformat_new_stream(self.inner, ptr::null()) };
+        if stream.is_null() {
+            return Err(Error::NewStream {
+                path: self.path.clone(),
+            });
+        }
+
+        unsafe {
+            let stream = stream.as_mut().unwrap();
+            let codecpar = stream.codecpar.as_mut().unwrap();
+
+            codecpar.codec_type = AVMediaType::AVMEDIA_TYPE_VIDEO;
+            codecpar.codec_id = cover_art.format.codec_id();
+            stream.disposition |= AV_DISPOSITION_ATTACHED_PIC;
+        }
+
+        Ok(Some(stream))
+    }
+
+    /// Tags are set on the file and the audio stream, as some containers (e.g. ogg) only store
+    /// tags per stream
+    fn set_metadata(&self, stream: *mut AVStream, metadata: &Metadata) {
+        unsafe {
+            metadata.write_dictionary(&mut self.inner.as_mut().unwrap().metadata);
+            metadata.write_dictionary(&mut stream.as_mut().unwrap().metadata);
+        }
+    }
+
+    fn write_cover_art(&self, stream: *mut AVStream, cover_art: &CoverArt) -> Result<(), Error> {
+        let mut packet = Packet::new();
+
+        let status =
+            unsafe { av_new_packet(packet.inner.as_mut_ptr(), cover_art.data.len() as i32) };
+        if status < 0 {
+            return Err(Error::AllocatePacket(AvError::new(status)));
+        }
+
+        unsafe {
+            let inner = packet.inner.as_mut_ptr().as_mut().unwrap();
+
+            ptr::copy_nonoverlapping(cover_art.data.as_ptr(), inner.data, cover_art.data.len());
+            inner.stream_index = stream.as_ref().unwrap().index;
+            inner.flags |= AV_PKT_FLAG_KEY;
+        }
+
+        // Takes ownership of the packets data
+        let status = unsafe { av_interleaved_write_frame(self.inner, packet.inner.as_mut_ptr()) };
+        if status < 0 {
+            return Err(Error::WritePacket(AvError::new(status)));
+        }
+
+        Ok(())
+    }
+
+    /// Open the output file, unless the container does its own IO
+    fn open_io(&mut self) -> Result<(), Error> {
+        if self.flags() & AVFMT_NOFILE != 0 {
+            return Ok(());
+        }
+
+        let c_path = CString::new(self.path.as_str()).unwrap();
+
+        let status = unsafe {
+            avio_open(
+                &mut self.inner.as_mut().unwrap().pb,
+                c_path.as_ptr(),
+                AVIO_FLAG_WRITE,
+            )
+        };
+        if status < 0 {
+            return Err(Error::OpenOutput {
+                path: self.path.clone(),
+                source: AvError::new(status),
+            });
+        }
+
+        self.io_opened = true;
+
+        Ok(())
+    }
+
+    fn write_header(&self) -> Result<(), Error> {
+        let status = unsafe { avformat_write_header(self.inner, ptr::null_mut()) };
+        if status < 0 {
+            return Err(Error::WriteHeader {
+                path: self.path.clone(),
+                source: AvError::new(status),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for OutputContext {
+    fn drop(&mut self) {
+        unsafe {
+            if self.io_opened {
+                avio_closep(&mut self.inner.as_mut().unwrap().pb);
+            }
+
+            avformat_free_context(self.inner);
+        }
+    }
+}
+
+struct EncoderContext {
+    inner: *mut AVCodecContext,
+}
+
+impl EncoderContext {
+    fn new(codec: *mut AVCodec, codec_name: &str) -> Result<EncoderContext, Error> {
+        let inner = unsafe { avcodec_alloc_context3(codec) };
+        if inner.is_null() {
+            return Err(Error::NullEncoderContext {
+                codec: codec_name.to_owned(),
+            });
+        }
+
+        Ok(EncoderContext { inner })
+    }
+}
+
+impl Drop for EncoderContext {
+    fn drop(&mut self) {
+        unsafe { avcodec_free_context(&mut self.inner) };
+    }
+}
+
+struct EncoderFrame {
+    inner: *mut AVFrame,
+}
+
+impl EncoderFrame {
+    fn new(format: AudioFormat, frame_size: i32) -> Result<EncoderFrame, Error> {
+        let inner = unsafe { av_frame_alloc() };
+        if inner.is_null() {
+            return Err(Error::NullFrame);
+        }
+
+        let frame = EncoderFrame { inner };
+
+        unsafe {
+            let inner = frame.inner.as_mut().unwrap();
+
+            inner.nb_samples = frame_size;
+            inner.format = format.sample_format as i32;
+            inner.channel_layout = format.channel_layout;
+            inner.channels = format.channels();
+            inner.sample_rate = format.sample_rate;
+        }
+
+        let status = unsafe { av_frame_get_buffer(frame.inner, 0) };
+        if status < 0 {
+            return Err(Error::AllocateFrame(AvError::new(status)));
+        }
+
+        Ok(frame)
+    }
+}
+
+impl Drop for EncoderFrame {
+    fn drop(&mut self) {
+        unsafe { av_frame_free(&mut self.inner) };
+    }
+}
+
+fn find_encoder(codec: Option<AudioCodec>, default: AVCodecID) -> Result<*mut AVCodec, Error> {
+    let encoder = match codec {
+        Some(codec) => codec
+            .encoder_names()
+            .iter()
+            .map(|name| {
+                let name = CString::new(*name).unwrap();
+                unsafe { avcodec_find_encoder_by_name(name.as_ptr()) }
+            })
+            .find(|encoder| !encoder.is_null()),
+        None => Some(unsafe { avcodec_find_encoder(default) }).filter(|encoder| !encoder.is_null()),
+    };
+
+    encoder.ok_or_else(|| Error::EncoderNotFound {
+        codec: match codec {
+            Some(codec) => format!("{:?}", codec),
+            None => format!("{:?}", default),
+        },
+    })
+}
+
+/// Prefer signed 16bit to avoid a conversion, otherwise take the codecs first choice
+fn select_sample_format(codec: &AVCodec) -> AVSampleFormat {
+    let formats = unsafe { terminated_list(codec.sample_fmts, AVSampleFormat::AV_SAMPLE_FMT_NONE) };
+
+    [
+        AVSampleFormat::AV_SAMPLE_FMT_S16,
+        AVSampleFormat::AV_SAMPLE_FMT_S16P,
+    ]
+    .iter()
+    .find(|format| formats.contains(format))
+    .or_else(|| formats.first())
+    .copied()
+    .unwrap_or(INPUT_FORMAT)
+}
+
+/// Keep the input rate if supported, otherwise the closest higher rate, otherwise the highest
+fn select_sample_rate(codec: &AVCodec, sample_rate: i32) -> i32 {
+    let rates = unsafe { terminated_list(codec.supported_samplerates, 0) };
+
+    if rates.is_empty() || rates.contains(&sample_rate) {
+        return sample_rate;
+    }
+
+    rates
+        .iter()
+        .filter(|&&rate| rate > sample_rate)
+        .min()
+        .or_else(|| rates.iter().max())
+        .copied()
+        .unwrap_or(sample_rate)
+}
+
+/// Keep the input layout if supported, otherwise the supported layout with the most channels
+/// not exceeding the input
+fn select_channel_layout(codec: &AVCodec, channel_layout: u64) -> u64 {
+    let layouts = unsafe { terminated_list(codec.channel_layouts, 0) };
+
+    if layouts.is_empty() || layouts.contains(&channel_layout) {
+        return channel_layout;
+    }
+
+    let channels = channel_layout.count_ones();
+
+    layouts
+        .iter()
+        .filter(|layout| layout.count_ones() <= channels)
+        .max_by_key(|layout| layout.count_ones())
+        .or_else(|| layouts.first())
+        .copied()
+        .unwrap_or(channel_layout)
+}
+
+/// Samples per frame the encoder expects, and whether the last frame may be shorter
+fn frame_size(codec: &AVCodec, codec_ctx: &EncoderContext) -> (i32, bool) {
+    let frame_size = unsafe { codec_ctx.inner.as_ref().unwrap().frame_size };
+    let capabilities = codec.capabilities as u32;
+
+    if frame_size <= 0 || capabilities & AV_CODEC_CAP_VARIABLE_FRAME_SIZE != 0 {
+        (DEFAULT_FRAME_SIZE, true)
+    } else {
+        (
+            frame_size,
+            capabilities & AV_CODEC_CAP_SMALL_LAST_FRAME != 0,
+        )
+    }
+}
+
+/// Collect a list ffmpeg terminates with `terminator`, a null list is empty
+unsafe fn terminated_list<T: Copy + PartialEq>(list: *const T, terminator: T) -> Vec<T> {
+    let mut items = vec![];
+
+    if list.is_null() {
+        return items;
+    }
+
+    let mut item = list;
+    while *item != terminator {
+        items.push(*item);
+        item = item.add(1);
+    }
+
+    items
+}
+
+fn print_encoder_info(codec_name: &str, format: AudioFormat, path: &str) {
+    let sample_fmt = unsafe { CStr::from_ptr(av_get_sample_fmt_name(format.sample_format)) };
+
+    info!("Encoder:       {}", codec_name);
+    info!("Sample Format: {}", sample_fmt.to_string_lossy());
+    info!("Sample Rate:   {}", format.sample_rate);
+    info!("Channels:      {}", format.channels());
+    info!("Output:        {}", path);
+}
@@ -1,31 +1,229 @@
+use ffmpeg_sys_next::av_strerror;
 use thiserror::Error;
 
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::c_char;
+use std::time::Duration;
+
+pub(crate) const AVERROR_EOF: i32 = -0x20_464_F45;
+pub(crate) const AVERROR_INVALIDDATA: i32 = -0x4144_4E49;
+pub(crate) const AVERROR_EAGAIN: i32 = -11;
+pub(crate) const AVERROR_ENOMEM: i32 = -12;
+pub(crate) const AVERROR_EDEADLK: i32 = -35;
+
+const ERROR_BUFFER_SIZE: usize = 128;
+
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("Failed to initialize format context")]
-    InitializeFormatContext,
-    #[error("Could not find stream in file")]
-    FindStreamInfo,
-    #[error("Could not find any audio stream")]
-    NoAudioStream,
-    #[error("Null codec pointer")]
-    NullCodec,
-    #[error("Null codec context pointer")]
-    NullCodecContext,
-    #[error("Copying params to codec context")]
-    CodecParamsToContext,
-    #[error("Failed to initialize decoder")]
-    InitializeDecoder,
+    #[error("Failed to initialize format context for {path:?}: {source}")]
+    InitializeFormatContext { path: String, source: AvError },
+    #[error("Could not find stream info in {path:?}: {source}")]
+    FindStreamInfo { path: String, source: AvError },
+    #[error("Could not find any audio stream in {path:?}")]
+    NoAudioStream { path: String },
+    #[error("Could not find an audio stream with {criteria} in {path:?}")]
+    NoMatchingAudioStream { path: String, criteria: String },
+    #[error("Could not find stream {stream_index} in {path:?}")]
+    NoStream { path: String, stream_index: i32 },
+    #[error("Null codec pointer for stream {stream_index}")]
+    NullCodec { stream_index: i32 },
+    #[error("Null codec context pointer for stream {stream_index}")]
+    NullCodecContext { stream_index: i32 },
+    #[error("Copying params of stream {stream_index} to codec context: {source}")]
+    CodecParamsToContext { stream_index: i32, source: AvError },
+    #[error("Failed to initialize decoder for stream {stream_index}: {source}")]
+    InitializeDecoder { stream_index: i32, source: AvError },
     #[error("Null frame pointer")]
     NullFrame,
     #[error("Error reading frame: {0}")]
-    ReadFrame(i32),
-    #[error("Error sending packet: {0}")]
-    SendPacket(i32),
+    ReadFrame(AvError),
+    #[error("Error sending packet of stream {stream_index}: {source}")]
+    SendPacket { stream_index: i32, source: AvError },
     #[error("Error draining decoder: {0}")]
-    DrainDecoder(i32),
-    #[error("Error receiving frame: {0}")]
-    ReceiveFrame(i32),
-    #[error("Failed to initialize swr context")]
-    InitializeSwr,
+    DrainDecoder(AvError),
+    #[error("Error receiving frame of stream {stream_index}: {source}")]
+    ReceiveFrame { stream_index: i32, source: AvError },
+    #[error("Failed to initialize swr context: {0}")]
+    InitializeSwr(AvError),
+    #[error("Failed to seek to {position:?}: {source}")]
+    Seek { position: Duration, source: AvError },
+    #[error("Seek index of stream {index_stream} at {index_sample_rate}Hz doesn't match stream {stream_index} at {sample_rate}Hz")]
+    SeekIndexMismatch {
+        stream_index: i32,
+        sample_rate: u32,
+        index_stream: usize,
+        index_sample_rate: u32,
+    },
+    #[error("Failed to initialize output context for {path:?}: {source}")]
+    InitializeOutputContext { path: String, source: AvError },
+    #[error("Could not find an encoder for {codec}")]
+    EncoderNotFound { codec: String },
+    #[error("Null codec context pointer for encoder {codec}")]
+    NullEncoderContext { codec: String },
+    #[error("Failed to initialize encoder {codec}: {source}")]
+    InitializeEncoder { codec: String, source: AvError },
+    #[error("Failed to add a stream to {path:?}")]
+    NewStream { path: String },
+    #[error("Copying codec context params to stream: {0}")]
+    CodecParamsFromContext(AvError),
+    #[error("Failed to open {path:?} for writing: {source}")]
+    OpenOutput { path: String, source: AvError },
+    #[error("Failed to write header to {path:?}: {source}")]
+    WriteHeader { path: String, source: AvError },
+    #[error("Error allocating frame buffer: {0}")]
+    AllocateFrame(AvError),
+    #[error("Error allocating packet: {0}")]
+    AllocatePacket(AvError),
+    #[error("Error converting samples: {0}")]
+    ConvertSamples(AvError),
+    #[error("Error sending frame: {0}")]
+    SendFrame(AvError),
+    #[error("Error receiving packet: {0}")]
+    ReceivePacket(AvError),
+    #[error("Error writing packet: {0}")]
+    WritePacket(AvError),
+    #[error("Error writing trailer: {0}")]
+    WriteTrailer(AvError),
+    #[error("Failed to initialize filter graph {filter:?}: {source}")]
+    InitializeFilter { filter: String, source: AvError },
+    #[error("Error filtering frame: {0}")]
+    Filter(AvError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[cfg(feature = "analysis_png")]
+    #[error("Error encoding PNG: {0}")]
+    EncodePng(#[from] png::EncodingError),
+}
+
+impl Error {
+    /// The raw `AVERROR` code returned by ffmpeg, if this error originated from an ffmpeg call
+    pub fn code(&self) -> Option<i32> {
+        self.av_error().map(AvError::code)
+    }
+
+    /// Category of the underlying ffmpeg error, if this error originated from an ffmpeg call
+    pub fn kind(&self) -> Option<ErrorKind> {
+        self.av_error().map(AvError::kind)
+    }
+
+    /// Whether decoding can continue after this error, see [`AvError::is_recoverable`](struct.AvError.html#method.is_recoverable)
+    pub fn is_recoverable(&self) -> bool {
+        self.av_error().is_some_and(AvError::is_recoverable)
+    }
+
+    fn av_error(&self) -> Option<AvError> {
+        match self {
+            Error::InitializeFormatContext { source, .. }
+            | Error::FindStreamInfo { source, .. }
+            | Error::CodecParamsToContext { source, .. }
+            | Error::InitializeDecoder { source, .. }
+            | Error::SendPacket { source, .. }
+            | Error::ReceiveFrame { source, .. }
+            | Error::Seek { source, .. }
+            | Error::InitializeOutputContext { source, .. }
+            | Error::InitializeEncoder { source, .. }
+            | Error::OpenOutput { source, .. }
+            | Error::WriteHeader { source, .. }
+            | Error::InitializeFilter { source, .. } => Some(*source),
+            Error::ReadFrame(source)
+            | Error::DrainDecoder(source)
+            | Error::InitializeSwr(source)
+            | Error::CodecParamsFromContext(source)
+            | Error::AllocateFrame(source)
+            | Error::AllocatePacket(source)
+            | Error::ConvertSamples(source)
+            | Error::SendFrame(source)
+            | Error::ReceivePacket(source)
+            | Error::WritePacket(source)
+            | Error::WriteTrailer(source)
+            | Error::Filter(source) => Some(*source),
+            Error::NoAudioStream { .. }
+            | Error::NoMatchingAudioStream { .. }
+            | Error::NoStream { .. }
+            | Error::SeekIndexMismatch { .. }
+            | Error::EncoderNotFound { .. }
+            | Error::NewStream { .. }
+            | Error::NullCodec { .. }
+            | Error::NullCodecContext { .. }
+            | Error::NullEncoderContext { .. }
+            | Error::NullFrame
+            | Error::Io(_) => None,
+            #[cfg(feature = "analysis_png")]
+            Error::EncodePng(_) => None,
+        }
+    }
+}
+
+/// Broad category of an ffmpeg error code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Resource temporarily unavailable (`EAGAIN`), more input or output is needed
+    Again,
+    /// End of file / stream reached
+    Eof,
+    /// Invalid data found when processing input, e.g. a corrupt packet
+    InvalidData,
+    /// Allocation failed (`ENOMEM`)
+    OutOfMemory,
+    /// Any other error
+    Other,
+}
+
+/// An ffmpeg status code (`AVERROR`), displayed using the message from `av_strerror`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AvError {
+    code: i32,
 }
+
+impl AvError {
+    pub fn new(code: i32) -> AvError {
+        AvError { code }
+    }
+
+    /// The raw `AVERROR` code
+    pub fn code(self) -> i32 {
+        self.code
+    }
+
+    pub fn kind(self) -> ErrorKind {
+        match self.code {
+            AVERROR_EAGAIN => ErrorKind::Again,
+            AVERROR_EOF => ErrorKind::Eof,
+            AVERROR_INVALIDDATA => ErrorKind::InvalidData,
+            AVERROR_ENOMEM => ErrorKind::OutOfMemory,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// `EAGAIN`, `EOF` and `INVALIDDATA` are recoverable: the stream can still be read,
+    /// possibly after skipping the offending packet. Everything else is fatal.
+    pub fn is_recoverable(self) -> bool {
+        match self.kind() {
+            ErrorKind::Again | ErrorKind::Eof | ErrorKind::InvalidData => true,
+            ErrorKind::OutOfMemory | ErrorKind::Other => false,
+        }
+    }
+
+    /// Human readable message for this code, as returned by `av_strerror`
+    pub fn message(self) -> String {
+        let mut buf = [0 as c_char; ERROR_BUFFER_SIZE];
+
+        let status = unsafe { av_strerror(self.code, buf.as_mut_ptr(), ERROR_BUFFER_SIZE as _) };
+        if status < 0 {
+            return format!("Unknown error code {}", self.code);
+        }
+
+        let message = unsafe { CStr::from_ptr(buf.as_ptr()) };
+
+        message.to_string_lossy().into_owned()
+    }
+}
+
+impl fmt::Display for AvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message(), self.code)
+    }
+}
+
+impl std::error::Error for AvError {}
@@ -0,0 +1,331 @@
+use crate::error::{AvError, Error, AVERROR_EAGAIN, AVERROR_ENOMEM, AVERROR_EOF};
+use crate::resample::AudioFormat;
+
+use ffmpeg_sys_next::{
+    av_buffersink_get_frame, av_buffersink_get_time_base, av_buffersrc_add_frame_flags,
+    av_frame_alloc, av_frame_free, av_frame_unref, av_get_sample_fmt_name, av_rescale_q, av_strdup,
+    avfilter_get_by_name, avfilter_graph_alloc, avfilter_graph_config,
+    avfilter_graph_create_filter, avfilter_graph_free, avfilter_graph_parse_ptr,
+    avfilter_inout_alloc, avfilter_inout_free, avfilter_register_all, AVFilterContext,
+    AVFilterGraph, AVFilterInOut, AVFrame, AVRational, AVSampleFormat, AV_BUFFERSRC_FLAG_KEEP_REF,
+    AV_NOPTS_VALUE,
+};
+use std::ffi::{CStr, CString};
+use std::ptr;
+use std::slice;
+
+use log::info;
+
+const OUTPUT_FORMAT: AVSampleFormat = AVSampleFormat::AV_SAMPLE_FMT_S16;
+
+/// Range of a single `atempo` filter, larger changes are chained
+const ATEMPO_RANGE: (f64, f64) = (0.5, 2.0);
+
+/// Filters changing the speed by `tempo` without changing pitch, and the pitch by `semitones`
+/// without changing speed
+///
+/// Pitch is shifted by playing the samples as if they were at a different rate, compensating
+/// the speed change with `atempo`. `sample_rate` is the rate of the stream.
+pub(crate) fn tempo_and_pitch(tempo: f64, semitones: f64, sample_rate: i32) -> Vec<String> {
+    let mut filters = vec![];
+    let mut atempo = tempo;
+
+    if semitones != 0.0 {
+        let factor = 2f64.powf(semitones / 12.0);
+
+        filters.push(format!(
+            "asetrate={},aresample={}",
+            (f64::from(sample_rate) * factor).round(),
+            sample_rate
+        ));
+        atempo /= factor;
+    }
+
+    while atempo > ATEMPO_RANGE.1 {
+        filters.push(format!("atempo={}", ATEMPO_RANGE.1));
+        atempo /= ATEMPO_RANGE.1;
+    }
+    while atempo < ATEMPO_RANGE.0 {
+        filters.push(format!("atempo={}", ATEMPO_RANGE.0));
+        atempo /= ATEMPO_RANGE.0;
+    }
+    if (atempo - 1.0).abs() > f64::EPSILON {
+        filters.push(format!("atempo={}", atempo));
+    }
+
+    filters
+}
+
+/// An avfilter graph between the decoder and the samples returned to the user
+///
+/// The graph is described as for ffmpegs `-af` option, e.g. `loudnorm,atempo=1.25`. Its output
+/// is always converted to interleaved signed 16bit at the rate and layout of the first decoded
+/// frame. If the decoded format changes mid-stream, the graph is flushed and rebuilt for the new
+/// input format.
+pub(crate) struct FilterGraph {
+    inner: *mut AVFilterGraph,
+    source: *mut AVFilterContext,
+    sink: *mut AVFilterContext,
+    frame: *mut AVFrame,
+    description: String,
+    input: AudioFormat,
+    output: AudioFormat,
+    time_base: AVRational,
+    /// Samples flushed from a graph that was replaced, returned before any new ones
+    pending: Vec<i16>,
+    pending_pts: Option<i64>,
+    flushed: bool,
+}
+
+impl FilterGraph {
+    /// `time_base` is the unit of the timestamps of frames sent to the graph, and of the ones
+    /// returned
+    pub(crate) fn new(
+        description: &str,
+        input: AudioFormat,
+        output: AudioFormat,
+        time_base: AVRational,
+    ) -> Result<FilterGraph, Error> {
+        unsafe { avfilter_register_all() };
+
+        let frame = unsafe { av_frame_alloc() };
+        if frame.is_null() {
+            return Err(Error::NullFrame);
+        }
+
+        let mut graph = FilterGraph {
+            inner: ptr::null_mut(),
+            source: ptr::null_mut(),
+            sink: ptr::null_mut(),
+            frame,
+            description: description.to_owned(),
+            input,
+            output: AudioFormat {
+                sample_format: OUTPUT_FORMAT,
+                ..output
+            },
+            time_base,
+            pending: vec![],
+            pending_pts: None,
+            flushed: false,
+        };
+        graph.configure()?;
+
+        Ok(graph)
+    }
+
+    /// (Re)create the graph for the current input format
+    fn configure(&mut self) -> Result<(), Error> {
+        unsafe { avfilter_graph_free(&mut self.inner) };
+
+        self.inner = unsafe { avfilter_graph_alloc() };
+        if self.inner.is_null() {
+            return Err(self.error(AvError::new(AVERROR_ENOMEM)));
+        }
+
+        let sample_fmt =
+            unsafe { CStr::from_ptr(av_get_sample_fmt_name(self.input.sample_format)) };
+        let source_args = format!(
+            "time_base={}/{}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
+            self.time_base.num,
+            self.time_base.den,
+            self.input.sample_rate,
+            sample_fmt.to_string_lossy(),
+            self.input.channel_layout,
+        );
+        self.source = self.create_filter("abuffer", "in", Some(&source_args))?;
+        self.sink = self.create_filter("abuffersink", "out", None)?;
+
+        // Pin the output format, so it stays the same whatever the filters and input do
+        let description = format!(
+            "{},aformat=sample_fmts=s16:sample_rates={}:channel_layouts=0x{:x}",
+            self.description, self.output.sample_rate, self.output.channel_layout,
+        );
+        let c_description = CString::new(description).unwrap();
+
+        // The open ends of the parsed graph, connected to the source and sink
+        let mut outputs = new_inout("in", self.source);
+        let mut inputs = new_inout("out", self.sink);
+
+        let status = unsafe {
+            avfilter_graph_parse_ptr(
+                self.inner,
+                c_description.as_ptr(),
+                &mut inputs,
+                &mut outputs,
+                ptr::null_mut(),
+            )
+        };
+
+        unsafe {
+            avfilter_inout_free(&mut inputs);
+            avfilter_inout_free(&mut outputs);
+        }
+
+        if status < 0 {
+            return Err(self.error(AvError::new(status)));
+        }
+
+        let status = unsafe { avfilter_graph_config(self.inner, ptr::null_mut()) };
+        if status < 0 {
+            return Err(self.error(AvError::new(status)));
+        }
+
+        self.flushed = false;
+
+        Ok(())
+    }
+
+    fn create_filter(
+        &self,
+        filter: &str,
+        name: &str,
+        args: Option<&str>,
+    ) -> Result<*mut AVFilterContext, Error> {
+        let c_filter = CString::new(filter).unwrap();
+        let c_name = CString::new(name).unwrap();
+        let c_args = args.map(|args| CString::new(args).unwrap());
+
+        let mut filter_ctx = ptr::null_mut();
+
+        let status = unsafe {
+            avfilter_graph_create_filter(
+                &mut filter_ctx,
+                avfilter_get_by_name(c_filter.as_ptr()),
+                c_name.as_ptr(),
+                c_args.as_ref().map_or(ptr::null(), |args| args.as_ptr()),
+                ptr::null_mut(),
+                self.inner,
+            )
+        };
+        if status < 0 {
+            return Err(self.error(AvError::new(status)));
+        }
+
+        Ok(filter_ctx)
+    }
+
+    fn error(&self, source: AvError) -> Error {
+        Error::InitializeFilter {
+            filter: self.description.clone(),
+            source,
+        }
+    }
+
+    /// Drop all buffered samples, e.g. after seeking
+    pub(crate) fn reset(&mut self) -> Result<(), Error> {
+        self.pending.clear();
+        self.pending_pts = None;
+
+        self.configure()
+    }
+
+    /// Feed a decoded frame of `format` to the graph. The frame is left untouched.
+    pub(crate) fn send_frame(
+        &mut self,
+        frame: *mut AVFrame,
+        format: AudioFormat,
+    ) -> Result<(), Error> {
+        if format != self.input {
+            info!("Decoded format changed, reconfiguring filter graph");
+
+            self.flush()?;
+            let (samples, pts) = self.receive_samples()?;
+            self.pending = samples;
+            self.pending_pts = pts;
+
+            self.input = format;
+            self.configure()?;
+        }
+
+        let status = unsafe {
+            av_buffersrc_add_frame_flags(self.source, frame, AV_BUFFERSRC_FLAG_KEEP_REF as i32)
+        };
+        if status < 0 {
+            return Err(Error::Filter(AvError::new(status)));
+        }
+
+        Ok(())
+    }
+
+    /// Signal the end of the input, so the remaining samples can be received. Does nothing if
+    /// already flushed.
+    pub(crate) fn flush(&mut self) -> Result<(), Error> {
+        if self.flushed {
+            return Ok(());
+        }
+        self.flushed = true;
+
+        let status = unsafe { av_buffersrc_add_frame_flags(self.source, ptr::null_mut(), 0) };
+        if status < 0 {
+            return Err(Error::Filter(AvError::new(status)));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn is_flushed(&self) -> bool {
+        self.flushed
+    }
+
+    /// All interleaved samples the graph has ready, along with the timestamp of the first one
+    pub(crate) fn receive_samples(&mut self) -> Result<(Vec<i16>, Option<i64>), Error> {
+        let mut samples = std::mem::take(&mut self.pending);
+        let mut pts = self.pending_pts.take();
+
+        let channels = self.output.channels() as usize;
+        let sink_time_base = unsafe { av_buffersink_get_time_base(self.sink) };
+
+        loop {
+            let status = unsafe { av_buffersink_get_frame(self.sink, self.frame) };
+            match status {
+                AVERROR_EAGAIN | AVERROR_EOF => break,
+                _ if status < 0 => return Err(Error::Filter(AvError::new(status))),
+                _ => {}
+            }
+
+            let frame = unsafe { self.frame.as_ref().unwrap() };
+
+            if samples.is_empty() && pts.is_none() && frame.pts != AV_NOPTS_VALUE {
+                pts = Some(unsafe { av_rescale_q(frame.pts, sink_time_base, self.time_base) });
+            }
+
+            let len = frame.nb_samples.max(0) as usize * channels;
+            samples.extend_from_slice(unsafe {
+                slice::from_raw_parts(*frame.extended_data as *const i16, len)
+            });
+
+            unsafe { av_frame_unref(self.frame) };
+        }
+
+        Ok((samples, pts))
+    }
+}
+
+impl Drop for FilterGraph {
+    fn drop(&mut self) {
+        unsafe {
+            avfilter_graph_free(&mut self.inner);
+            av_frame_free(&mut self.frame);
+        }
+    }
+}
+
+/// A graph end point named `name`, attached to the first pad of `filter_ctx`
+fn new_inout(name: &str, filter_ctx: *mut AVFilterContext) -> *mut AVFilterInOut {
+    let c_name = CString::new(name).unwrap();
+
+    unsafe {
+        let inout = avfilter_inout_alloc();
+
+        if let Some(inout) = inout.as_mut() {
+            // Freed along with the list
+            inout.name = av_strdup(c_name.as_ptr());
+            inout.filter_ctx = filter_ctx;
+            inout.pad_idx = 0;
+            inout.next = ptr::null_mut();
+        }
+
+        inout
+    }
+}
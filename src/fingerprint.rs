@@ -0,0 +1,546 @@
+use crate::decoder::Decoder;
+use crate::error::Error;
+use crate::frame::{duration_to_samples, samples_to_duration, AudioFrame};
+use crate::resample::{AudioFormat, Resampler};
+
+use ffmpeg_sys_next::{AVSampleFormat, AV_CH_LAYOUT_MONO};
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Chromaprint analyses mono audio at this rate
+const SAMPLE_RATE: u32 = 11025;
+const FRAME_SIZE: usize = 4096;
+const HOP_SIZE: usize = FRAME_SIZE / 3;
+const MIN_FREQ: f64 = 28.0;
+const MAX_FREQ: f64 = 3520.0;
+const NUM_BANDS: usize = 12;
+
+/// Smooths the chroma features over time
+const CHROMA_FILTER: [f64; 5] = [0.25, 0.75, 1.0, 0.75, 0.25];
+/// Chroma vectors with a smaller norm are silence and zeroed
+const MIN_CHROMA_NORM: f64 = 0.01;
+
+/// `CHROMAPRINT_ALGORITHM_TEST2`, the default and what AcoustID expects
+const ALGORITHM: u8 = 1;
+/// Length of audio fingerprinted by default, the same as `fpcalc`
+const DEFAULT_MAX_DURATION: Duration = Duration::from_secs(120);
+
+const GRAY_CODE: [u32; 4] = [0, 1, 3, 2];
+
+/// Filter type, y, height, width and quantizer thresholds of the classifiers of the
+/// `TEST2` algorithm. Each classifier adds 2 bits to a subfingerprint.
+#[rustfmt::skip]
+const CLASSIFIERS: [(u8, usize, usize, usize, [f64; 3]); 16] = [
+    (0, 4, 3, 15, [1.98215, 2.35817, 2.63523]),
+    (4, 4, 6, 15, [-1.03809, -0.651211, -0.282167]),
+    (1, 0, 4, 16, [-0.298702, 0.119262, 0.558497]),
+    (3, 8, 2, 12, [-0.105439, 0.0153946, 0.135898]),
+    (3, 4, 4, 8, [-0.142891, 0.0258736, 0.200632]),
+    (4, 0, 3, 5, [-0.826319, -0.590612, -0.368214]),
+    (1, 2, 2, 9, [-0.557409, -0.233035, 0.0534525]),
+    (2, 7, 3, 4, [-0.0646826, 0.00620476, 0.0784847]),
+    (2, 6, 2, 16, [-0.192387, -0.029699, 0.215855]),
+    (2, 1, 3, 2, [-0.0397818, -0.00568076, 0.0292026]),
+    (5, 10, 1, 15, [-0.53823, -0.369934, -0.190235]),
+    (3, 6, 2, 10, [-0.124877, 0.0296483, 0.139239]),
+    (2, 1, 1, 14, [-0.101475, 0.0225617, 0.126846]),
+    (3, 5, 6, 4, [-0.0799915, -0.00729616, 0.063262]),
+    (1, 9, 2, 12, [-0.272556, 0.019424, 0.302559]),
+    (3, 4, 2, 14, [-0.164292, -0.0321188, 0.0846339]),
+];
+
+/// Number of chroma frames the classifiers look at
+const MAX_FILTER_WIDTH: usize = 16;
+
+/// A Chromaprint fingerprint, computed like `fpcalc` with the default algorithm
+///
+/// Audio is resampled with swresample instead of Chromaprint's own resampler, so fingerprints
+/// are close to those of `fpcalc` but not bit-exact. AcoustID matches fingerprints by
+/// similarity, so lookups still find the recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// One subfingerprint per hop of 1365 samples at 11025 Hz
+    pub raw: Vec<u32>,
+    /// Duration of the whole file, as AcoustID lookups expect
+    pub duration: Duration,
+}
+
+impl Fingerprint {
+    /// Compressed and base64 encoded, the form AcoustID accepts and `fpcalc` prints
+    pub fn encode(&self) -> String {
+        base64_encode(&compress(&self.raw))
+    }
+}
+
+/// Fingerprint the first 2 minutes of the file
+pub fn fingerprint(path: impl AsRef<Path>) -> Result<Fingerprint, Error> {
+    fingerprint_with(path, Some(DEFAULT_MAX_DURATION))
+}
+
+/// Fingerprint the first `max_duration` of the file, or all of it if `None`
+pub fn fingerprint_with(
+    path: impl AsRef<Path>,
+    max_duration: Option<Duration>,
+) -> Result<Fingerprint, Error> {
+    let mut decoder = Decoder::open(path)?;
+    let mut fingerprinter = Fingerprinter::for_decoder(&decoder)?;
+
+    let channels = decoder._channels().max(1) as usize;
+    let sample_rate = decoder._sample_rate();
+    let mut remaining = max_duration.map(|duration| duration_to_samples(duration, sample_rate));
+
+    let mut num_samples = 0;
+    while let Some(frame) = decoder.next_frame() {
+        num_samples += frame.num_samples() as u64;
+
+        match &mut remaining {
+            Some(0) => {}
+            Some(remaining) => {
+                let len = (frame.num_samples() as u64).min(*remaining);
+                *remaining -= len;
+
+                fingerprinter.add_samples(&frame.samples[..len as usize * channels])?;
+            }
+            None => fingerprinter.add_frame(&frame)?,
+        }
+    }
+
+    if let Some(error) = decoder.take_error() {
+        return Err(error);
+    }
+
+    Ok(Fingerprint {
+        raw: fingerprinter.finish()?,
+        duration: decoder
+            .duration()
+            .unwrap_or_else(|| samples_to_duration(num_samples, sample_rate)),
+    })
+}
+
+/// Computes a Chromaprint fingerprint from decoded samples as they come in
+///
+/// Samples are mixed down to mono and resampled to 11025 Hz with swresample before analysis,
+/// so the result is close to but not bit-exact with Chromaprint's, see
+/// [`Fingerprint`](struct.Fingerprint.html).
+pub struct Fingerprinter {
+    channels: usize,
+    resampler: Option<Resampler>,
+    window: Vec<f64>,
+    fft: Arc<dyn Fft<f64>>,
+    buffer: Vec<Complex<f64>>,
+    /// Mono samples at 11025 Hz not yet moved past by the hop
+    pending: Vec<i16>,
+    /// Which band each FFT bin adds to
+    notes: Vec<usize>,
+    /// The last chroma vectors, for smoothing
+    chroma: VecDeque<[f64; NUM_BANDS]>,
+    /// Summed area table of the smoothed chroma vectors
+    image: Vec<[f64; NUM_BANDS]>,
+    fingerprint: Vec<u32>,
+}
+
+impl Fingerprinter {
+    pub fn new(channels: u16, sample_rate: u32) -> Result<Fingerprinter, Error> {
+        let resampler = if sample_rate != SAMPLE_RATE {
+            let mono = |sample_rate| {
+                AudioFormat::new(
+                    AV_CH_LAYOUT_MONO,
+                    1,
+                    AVSampleFormat::AV_SAMPLE_FMT_S16,
+                    sample_rate as i32,
+                )
+            };
+
+            Some(Resampler::new(mono(sample_rate), mono(SAMPLE_RATE))?)
+        } else {
+            None
+        };
+
+        // Hamming window scaled so samples are in `-1.0..=1.0`
+        let window = (0..FRAME_SIZE)
+            .map(|n| {
+                (0.54 - 0.46 * (2.0 * PI * n as f64 / (FRAME_SIZE - 1) as f64).cos())
+                    / f64::from(i16::MAX)
+            })
+            .collect();
+
+        let min_index = frequency_to_index(MIN_FREQ).max(1);
+        let max_index = frequency_to_index(MAX_FREQ).min(FRAME_SIZE / 2);
+        let notes = (0..max_index)
+            .map(|index| {
+                if index < min_index {
+                    return NUM_BANDS;
+                }
+
+                let frequency = index as f64 * f64::from(SAMPLE_RATE) / FRAME_SIZE as f64;
+                let octave = (frequency / (440.0 / 16.0)).log2();
+
+                (NUM_BANDS as f64 * octave.fract()) as usize
+            })
+            .collect();
+
+        Ok(Fingerprinter {
+            channels: channels.max(1) as usize,
+            resampler,
+            window,
+            fft: FftPlanner::new().plan_fft_forward(FRAME_SIZE),
+            buffer: vec![Complex::default(); FRAME_SIZE],
+            pending: vec![],
+            notes,
+            chroma: VecDeque::with_capacity(CHROMA_FILTER.len()),
+            image: vec![],
+            fingerprint: vec![],
+        })
+    }
+
+    /// For the samples returned by `decoder`
+    pub fn for_decoder(decoder: &Decoder) -> Result<Fingerprinter, Error> {
+        Fingerprinter::new(decoder._channels(), decoder._sample_rate())
+    }
+
+    pub fn add_frame(&mut self, frame: &AudioFrame) -> Result<(), Error> {
+        self.add_samples(&frame.samples)
+    }
+
+    /// Add interleaved samples. The length must be a multiple of the number of channels.
+    pub fn add_samples(&mut self, samples: &[i16]) -> Result<(), Error> {
+        let mono: Vec<i16> = samples
+            .chunks_exact(self.channels)
+            .map(|frame| {
+                let sum: i32 = frame.iter().map(|sample| i32::from(*sample)).sum();
+                (sum / self.channels as i32) as i16
+            })
+            .collect();
+
+        match &mut self.resampler {
+            Some(resampler) => {
+                let resampled = resampler.convert(&mono)?;
+                self.add_mono(&resampled);
+            }
+            None => self.add_mono(&mono),
+        }
+
+        Ok(())
+    }
+
+    /// The subfingerprints of all samples added
+    pub fn finish(mut self) -> Result<Vec<u32>, Error> {
+        if let Some(resampler) = &mut self.resampler {
+            let resampled = resampler.flush()?;
+            self.add_mono(&resampled);
+        }
+
+        Ok(self.fingerprint)
+    }
+
+    fn add_mono(&mut self, samples: &[i16]) {
+        self.pending.extend_from_slice(samples);
+
+        while self.pending.len() >= FRAME_SIZE {
+            let chroma = self.chroma();
+            self.add_chroma(chroma);
+
+            self.pending.drain(..HOP_SIZE);
+        }
+    }
+
+    /// Energy per semitone of the first frame of pending samples, octaves folded together
+    fn chroma(&mut self) -> [f64; NUM_BANDS] {
+        for ((value, sample), coefficient) in
+            self.buffer.iter_mut().zip(&self.pending).zip(&self.window)
+        {
+            *value = Complex::new(f64::from(*sample) * coefficient, 0.0);
+        }
+
+        self.fft.process(&mut self.buffer);
+
+        let mut chroma = [0.0; NUM_BANDS];
+        for (note, value) in self.notes.iter().zip(&self.buffer) {
+            if let Some(band) = chroma.get_mut(*note) {
+                *band += value.norm_sqr();
+            }
+        }
+
+        chroma
+    }
+
+    fn add_chroma(&mut self, chroma: [f64; NUM_BANDS]) {
+        if self.chroma.len() == CHROMA_FILTER.len() {
+            self.chroma.pop_front();
+        }
+        self.chroma.push_back(chroma);
+
+        if self.chroma.len() < CHROMA_FILTER.len() {
+            return;
+        }
+
+        let mut smoothed = [0.0; NUM_BANDS];
+        for (chroma, coefficient) in self.chroma.iter().zip(&CHROMA_FILTER) {
+            for (band, value) in smoothed.iter_mut().zip(chroma) {
+                *band += value * coefficient;
+            }
+        }
+
+        let norm = smoothed
+            .iter()
+            .map(|value| value * value)
+            .sum::<f64>()
+            .sqrt();
+        for band in &mut smoothed {
+            *band = if norm < MIN_CHROMA_NORM {
+                0.0
+            } else {
+                *band / norm
+            };
+        }
+
+        self.add_image_row(smoothed);
+    }
+
+    fn add_image_row(&mut self, row: [f64; NUM_BANDS]) {
+        let previous = self.image.last().copied().unwrap_or([0.0; NUM_BANDS]);
+
+        let mut integral = [0.0; NUM_BANDS];
+        let mut row_sum = 0.0;
+        for band in 0..NUM_BANDS {
+            row_sum += row[band];
+            integral[band] = previous[band] + row_sum;
+        }
+        self.image.push(integral);
+
+        if self.image.len() >= MAX_FILTER_WIDTH {
+            let subfingerprint = self.subfingerprint(self.image.len() - MAX_FILTER_WIDTH);
+            self.fingerprint.push(subfingerprint);
+        }
+    }
+
+    fn subfingerprint(&self, x: usize) -> u32 {
+        CLASSIFIERS
+            .iter()
+            .fold(0, |bits, (kind, y, height, width, thresholds)| {
+                let value = self.filter(*kind, x, *y, *width, *height);
+                (bits << 2) | GRAY_CODE[quantize(value, thresholds)]
+            })
+    }
+
+    /// Compares areas of the chroma image starting at frame `x` and band `y`
+    fn filter(&self, kind: u8, x: usize, y: usize, w: usize, h: usize) -> f64 {
+        let area = |x1, y1, x2, y2| self.area(x1, y1, x2, y2);
+
+        let (a, b) = match kind {
+            0 => (area(x, y, x + w, y + h), 0.0),
+            1 => (
+                area(x, y + h / 2, x + w, y + h),
+                area(x, y, x + w, y + h / 2),
+            ),
+            2 => (
+                area(x + w / 2, y, x + w, y + h),
+                area(x, y, x + w / 2, y + h),
+            ),
+            3 => (
+                area(x, y + h / 2, x + w / 2, y + h) + area(x + w / 2, y, x + w, y + h / 2),
+                area(x, y, x + w / 2, y + h / 2) + area(x + w / 2, y + h / 2, x + w, y + h),
+            ),
+            4 => (
+                area(x, y + h / 3, x + w, y + 2 * (h / 3)),
+                area(x, y, x + w, y + h / 3) + area(x, y + 2 * (h / 3), x + w, y + h),
+            ),
+            _ => (
+                area(x + w / 3, y, x + 2 * (w / 3), y + h),
+                area(x, y, x + w / 3, y + h) + area(x + 2 * (w / 3), y, x + w, y + h),
+            ),
+        };
+
+        ((1.0 + a) / (1.0 + b)).ln()
+    }
+
+    /// Sum of frames `x1..x2` and bands `y1..y2`
+    fn area(&self, x1: usize, y1: usize, x2: usize, y2: usize) -> f64 {
+        if x2 <= x1 || y2 <= y1 {
+            return 0.0;
+        }
+
+        let at = |x: usize, y: usize| self.image[x][y];
+
+        let mut area = at(x2 - 1, y2 - 1);
+        if x1 > 0 {
+            area -= at(x1 - 1, y2 - 1);
+        }
+        if y1 > 0 {
+            area -= at(x2 - 1, y1 - 1);
+        }
+        if x1 > 0 && y1 > 0 {
+            area += at(x1 - 1, y1 - 1);
+        }
+
+        area
+    }
+}
+
+fn frequency_to_index(frequency: f64) -> usize {
+    (FRAME_SIZE as f64 * frequency / f64::from(SAMPLE_RATE)).round() as usize
+}
+
+fn quantize(value: f64, thresholds: &[f64; 3]) -> usize {
+    thresholds
+        .iter()
+        .take_while(|threshold| value >= **threshold)
+        .count()
+}
+
+/// Chromaprint's compressed format: a header with the algorithm and length, then the positions
+/// of the bits that changed from the previous subfingerprint, packed as 3bit values with
+/// overflow in a trailing block of 5bit values
+fn compress(fingerprint: &[u32]) -> Vec<u8> {
+    const MAX_NORMAL_VALUE: u8 = 7;
+
+    let mut normal = vec![];
+    let mut previous = 0;
+    for subfingerprint in fingerprint {
+        let mut changed = subfingerprint ^ previous;
+        previous = *subfingerprint;
+
+        let mut bit = 1;
+        let mut last_bit = 0;
+        while changed != 0 {
+            if changed & 1 != 0 {
+                normal.push(bit - last_bit);
+                last_bit = bit;
+            }
+            changed >>= 1;
+            bit += 1;
+        }
+        normal.push(0);
+    }
+
+    let len = fingerprint.len();
+    let mut output = vec![ALGORITHM, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+
+    let mut bits = BitWriter::new(&mut output);
+    for value in &normal {
+        bits.write(u32::from((*value).min(MAX_NORMAL_VALUE)), 3);
+    }
+    bits.flush();
+
+    let mut bits = BitWriter::new(&mut output);
+    for value in normal.iter().filter(|value| **value >= MAX_NORMAL_VALUE) {
+        bits.write(u32::from(value - MAX_NORMAL_VALUE), 5);
+    }
+    bits.flush();
+
+    output
+}
+
+/// Packs values least significant bit first
+struct BitWriter<'a> {
+    output: &'a mut Vec<u8>,
+    buffer: u32,
+    len: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(output: &'a mut Vec<u8>) -> BitWriter<'a> {
+        BitWriter {
+            output,
+            buffer: 0,
+            len: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, bits: u32) {
+        self.buffer |= value << self.len;
+        self.len += bits;
+
+        while self.len >= 8 {
+            self.output.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.len > 0 {
+            self.output.push(self.buffer as u8);
+            self.buffer = 0;
+            self.len = 0;
+        }
+    }
+}
+
+/// URL safe base64 without padding, as used by Chromaprint
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (index, byte)| {
+            group | u32::from(*byte) << (16 - 8 * index)
+        });
+
+        for index in 0..=chunk.len() {
+            let value = (group >> (18 - 6 * index)) & 0x3f;
+            encoded.push(char::from(ALPHABET[value as usize]));
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compressed bits after the 4 byte header
+    fn compressed_bits(fingerprint: &[u32]) -> Vec<u8> {
+        let compressed = compress(fingerprint);
+        let len = fingerprint.len();
+        assert_eq!(
+            &compressed[..4],
+            &[ALGORITHM, (len >> 16) as u8, (len >> 8) as u8, len as u8]
+        );
+
+        compressed[4..].to_vec()
+    }
+
+    #[test]
+    fn compress_chromaprint_vectors() {
+        // From Chromaprint's test_fingerprint_compressor.cpp
+        assert_eq!(compressed_bits(&[1]), [1]);
+        assert_eq!(compressed_bits(&[7]), [73, 0]);
+        assert_eq!(compressed_bits(&[1 << 6]), [7, 0]);
+        assert_eq!(compressed_bits(&[1 << 8]), [7, 2]);
+        assert_eq!(compressed_bits(&[1, 0]), [65, 0]);
+        assert_eq!(compressed_bits(&[1, 1]), [1, 0]);
+        assert_eq!(compress(&[]), [ALGORITHM, 0, 0, 0]);
+    }
+
+    #[test]
+    fn base64_chromaprint_vectors() {
+        // From Chromaprint's test_base64.cpp
+        assert_eq!(base64_encode(b"x"), "eA");
+        assert_eq!(base64_encode(b"xx"), "eHg");
+        assert_eq!(base64_encode(b"xxx"), "eHh4");
+        assert_eq!(base64_encode(b"xxxx"), "eHh4eA");
+        assert_eq!(base64_encode(b"xxxxx"), "eHh4eHg");
+        assert_eq!(base64_encode(b"xxxxxx"), "eHh4eHh4");
+        assert_eq!(base64_encode(b"\xff\xee"), "_-4");
+        assert_eq!(base64_encode(b""), "");
+    }
+
+    #[test]
+    fn encode() {
+        let fingerprint = Fingerprint {
+            raw: vec![1],
+            duration: Duration::from_secs(1),
+        };
+
+        assert_eq!(fingerprint.encode(), "AQAAAQE");
+    }
+}
@@ -0,0 +1,337 @@
+use crate::error::{AvError, Error, AVERROR_EOF};
+use crate::metadata::Metadata;
+use crate::stream_selector::StreamSelector;
+
+use ffmpeg_sys_next::{
+    av_init_packet, av_packet_move_ref, av_packet_unref, av_read_frame, avformat_close_input,
+    avformat_find_stream_info, avformat_open_input, AVCodecParameters, AVFormatContext,
+    AVMediaType, AVPacket, AVRational, AVStream,
+};
+use log::warn;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::CString;
+use std::ptr;
+use std::slice;
+use std::time::Duration;
+
+/// Packets queued per stream of a `SharedInput` before the oldest are dropped, some 20 seconds
+/// of audio for most codecs
+const MAX_QUEUED_PACKETS: usize = 1024;
+
+pub(crate) struct FormatContext {
+    pub(crate) inner: *mut AVFormatContext,
+    pub(crate) path: String,
+}
+
+impl FormatContext {
+    pub(crate) fn open(path: &str) -> Result<FormatContext, Error> {
+        let mut inner = std::ptr::null_mut::<AVFormatContext>();
+
+        let c_path = CString::new(path).unwrap();
+
+        let status = unsafe {
+            avformat_open_input(
+                &mut inner,
+                c_path.as_ptr(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+        if status != 0 {
+            return Err(Error::InitializeFormatContext {
+                path: path.to_owned(),
+                source: AvError::new(status),
+            });
+        }
+
+        Ok(FormatContext {
+            inner,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Look at first few frames to determine stream info
+    pub(crate) fn find_stream_info(&self) -> Result<(), Error> {
+        let status = unsafe { avformat_find_stream_info(self.inner, ptr::null_mut()) };
+        if status < 0 {
+            return Err(Error::FindStreamInfo {
+                path: self.path.clone(),
+                source: AvError::new(status),
+            });
+        }
+        Ok(())
+    }
+
+    ///  Get the first audio stream
+    pub(crate) fn get_audio_stream(&self) -> Result<Stream, Error> {
+        self.audio_streams()
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::NoAudioStream {
+                path: self.path.clone(),
+            })
+    }
+
+    /// Get all audio streams, in the order of the file
+    pub(crate) fn audio_streams(&self) -> Vec<Stream> {
+        self.streams()
+            .iter()
+            .map(|stream| Stream::new(*stream, unsafe { stream.as_ref().unwrap().index }))
+            .filter(|stream| stream.codec_type() == AVMediaType::AVMEDIA_TYPE_AUDIO)
+            .collect()
+    }
+
+    /// Get the audio stream picked by `selector`
+    pub(crate) fn select_audio_stream(&self, selector: &StreamSelector) -> Result<Stream, Error> {
+        let streams = self.audio_streams();
+
+        if streams.is_empty() {
+            return Err(Error::NoAudioStream {
+                path: self.path.clone(),
+            });
+        }
+
+        selector
+            .select(streams)
+            .ok_or_else(|| Error::NoMatchingAudioStream {
+                path: self.path.clone(),
+                criteria: selector.describe(),
+            })
+    }
+
+    /// Get the stream at `index`, of any media type
+    pub(crate) fn get_stream(&self, index: usize) -> Result<Stream, Error> {
+        let streams = self.streams();
+
+        let stream = streams.get(index).ok_or_else(|| Error::NoStream {
+            path: self.path.clone(),
+            stream_index: index as i32,
+        })?;
+
+        Ok(Stream::new(*stream, index as i32))
+    }
+
+    pub(crate) fn streams(&self) -> &[*mut AVStream] {
+        let num_streams = unsafe { self.inner.as_ref().unwrap().nb_streams };
+        let streams = unsafe { self.inner.as_ref().unwrap().streams };
+
+        unsafe { slice::from_raw_parts(streams, num_streams as usize) }
+    }
+
+    pub(crate) fn metadata(&self) -> Metadata {
+        Metadata::from_dictionary(unsafe { self.inner.as_ref().unwrap().metadata })
+    }
+
+    /// Duration of the whole file in `AV_TIME_BASE` units, `AV_NOPTS_VALUE` if unknown
+    pub(crate) fn duration(&self) -> i64 {
+        unsafe { self.inner.as_ref().unwrap().duration }
+    }
+
+    pub(crate) fn read_packet(&self, packet: &mut Packet) -> ReadFrameStatus {
+        let status = unsafe { av_read_frame(self.inner, packet.inner.as_mut_ptr()) };
+
+        match status {
+            AVERROR_EOF => ReadFrameStatus::Eof,
+            _ if status != 0 => ReadFrameStatus::Other(status),
+            _ => ReadFrameStatus::Ok,
+        }
+    }
+}
+
+impl Drop for FormatContext {
+    fn drop(&mut self) {
+        // Close the input.
+        unsafe { avformat_close_input(&mut self.inner) };
+    }
+}
+
+pub(crate) struct Stream {
+    pub(crate) inner: *mut AVStream,
+    pub(crate) index: i32,
+}
+
+impl Stream {
+    fn new(inner: *mut AVStream, index: i32) -> Stream {
+        Stream { inner, index }
+    }
+
+    pub(crate) fn codec_parameters(&self) -> *mut AVCodecParameters {
+        unsafe { self.inner.as_ref().unwrap().codecpar }
+    }
+
+    pub(crate) fn codec_type(&self) -> AVMediaType {
+        unsafe { self.codec_parameters().as_ref().unwrap().codec_type }
+    }
+
+    /// `AV_DISPOSITION_*` flags
+    pub(crate) fn disposition(&self) -> i32 {
+        unsafe { self.inner.as_ref().unwrap().disposition }
+    }
+
+    pub(crate) fn sample_rate(&self) -> u32 {
+        unsafe { self.codec_parameters().as_ref().unwrap().sample_rate.max(0) as u32 }
+    }
+
+    pub(crate) fn time_base(&self) -> AVRational {
+        unsafe { self.inner.as_ref().unwrap().time_base }
+    }
+
+    /// Timestamp of the first sample in units of the streams time base, `AV_NOPTS_VALUE` if
+    /// unknown
+    pub(crate) fn start_time(&self) -> i64 {
+        unsafe { self.inner.as_ref().unwrap().start_time }
+    }
+
+    /// Duration in units of the streams time base, `AV_NOPTS_VALUE` if unknown
+    pub(crate) fn duration(&self) -> i64 {
+        unsafe { self.inner.as_ref().unwrap().duration }
+    }
+
+    pub(crate) fn metadata(&self) -> Metadata {
+        Metadata::from_dictionary(unsafe { self.inner.as_ref().unwrap().metadata })
+    }
+}
+
+pub(crate) struct Packet {
+    pub(crate) inner: std::mem::MaybeUninit<AVPacket>,
+}
+
+impl Packet {
+    pub(crate) fn new() -> Packet {
+        let mut packet = std::mem::MaybeUninit::uninit();
+
+        unsafe { av_init_packet(packet.as_mut_ptr()) };
+
+        Packet { inner: packet }
+    }
+
+    pub(crate) fn get(&self) -> &AVPacket {
+        unsafe { self.inner.as_ptr().as_ref().unwrap() }
+    }
+
+    pub(crate) fn stream_index(&self) -> i32 {
+        self.get().stream_index
+    }
+
+    pub(crate) fn reset(&mut self) {
+        unsafe { av_packet_unref(self.inner.as_mut_ptr()) };
+    }
+
+    /// Take over the data of `other`, leaving it blank. This packet must be blank.
+    pub(crate) fn move_from(&mut self, other: &mut Packet) {
+        unsafe { av_packet_move_ref(self.inner.as_mut_ptr(), other.inner.as_mut_ptr()) };
+    }
+}
+
+/// A file read by the decoders of several of its streams at once
+///
+/// Each packet is read once, and queued for the decoder of its stream until that asks for it.
+/// Packets of streams without a decoder, or whose decoder was dropped, are discarded. A decoder
+/// falling more than `MAX_QUEUED_PACKETS` behind the one reading loses its oldest packets, so
+/// memory stays bounded if some decoders are never read.
+pub(crate) struct SharedInput {
+    pub(crate) format_ctx: FormatContext,
+    queues: HashMap<i32, VecDeque<Packet>>,
+    /// Streams that lost packets, to only warn once
+    overflowed: HashSet<i32>,
+    packet: Packet,
+    /// Number of seeks so far, so the other decoders notice and follow
+    pub(crate) seek_generation: u64,
+    pub(crate) seek_position: Duration,
+}
+
+impl SharedInput {
+    pub(crate) fn new(format_ctx: FormatContext, stream_indices: &[i32]) -> SharedInput {
+        SharedInput {
+            format_ctx,
+            queues: stream_indices
+                .iter()
+                .map(|index| (*index, VecDeque::new()))
+                .collect(),
+            overflowed: HashSet::new(),
+            packet: Packet::new(),
+            seek_generation: 0,
+            seek_position: Duration::default(),
+        }
+    }
+
+    /// Read the next packet of the stream at `stream_index` into `packet`, queueing those of
+    /// the other streams read on the way
+    pub(crate) fn read_packet(
+        &mut self,
+        stream_index: i32,
+        packet: &mut Packet,
+    ) -> ReadFrameStatus {
+        if let Some(mut queued) = self
+            .queues
+            .get_mut(&stream_index)
+            .and_then(VecDeque::pop_front)
+        {
+            packet.move_from(&mut queued);
+            return ReadFrameStatus::Ok;
+        }
+
+        loop {
+            match self.format_ctx.read_packet(&mut self.packet) {
+                ReadFrameStatus::Ok => {}
+                status => return status,
+            }
+
+            let index = self.packet.stream_index();
+            if index == stream_index {
+                packet.move_from(&mut self.packet);
+                return ReadFrameStatus::Ok;
+            }
+
+            match self.queues.get_mut(&index) {
+                Some(queue) => {
+                    if queue.len() >= MAX_QUEUED_PACKETS {
+                        if self.overflowed.insert(index) {
+                            warn!("Dropping packets of stream {}, which isn't read", index);
+                        }
+                        if let Some(mut oldest) = queue.pop_front() {
+                            oldest.reset();
+                        }
+                    }
+
+                    let mut queued = Packet::new();
+                    queued.move_from(&mut self.packet);
+                    queue.push_back(queued);
+                }
+                None => self.packet.reset(),
+            }
+        }
+    }
+
+    /// Drop all queued packets, e.g. after seeking
+    pub(crate) fn clear_queues(&mut self) {
+        for queue in self.queues.values_mut() {
+            for mut packet in queue.drain(..) {
+                packet.reset();
+            }
+        }
+    }
+
+    /// Stop queueing packets for the stream at `stream_index`, once its decoder is dropped
+    pub(crate) fn remove_stream(&mut self, stream_index: i32) {
+        if let Some(queue) = self.queues.remove(&stream_index) {
+            for mut packet in queue {
+                packet.reset();
+            }
+        }
+    }
+}
+
+unsafe impl Send for SharedInput {}
+
+impl Drop for SharedInput {
+    fn drop(&mut self) {
+        self.clear_queues();
+    }
+}
+
+pub(crate) enum ReadFrameStatus {
+    Ok,
+    Eof,
+    Other(i32),
+}
@@ -0,0 +1,211 @@
+use ffmpeg_sys_next::{av_rescale_q, AVRational, AV_NOPTS_VALUE};
+
+use std::time::Duration;
+
+/// A block of decoded samples, interleaved signed 16bit, along with where it is presented
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioFrame {
+    pub samples: Vec<i16>,
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// `None` if neither the frame nor any previous frame carried a timestamp
+    pub timestamp: Option<Timestamp>,
+}
+
+impl AudioFrame {
+    /// Number of samples per channel
+    pub fn num_samples(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    pub fn duration(&self) -> Duration {
+        samples_to_duration(self.num_samples() as u64, self.sample_rate)
+    }
+
+    /// Samples split by channel and scaled to `-1.0..1.0`
+    pub fn planar_f32(&self) -> Vec<Vec<f32>> {
+        let channels = self.channels.max(1) as usize;
+
+        (0..channels)
+            .map(|channel| {
+                self.samples
+                    .iter()
+                    .skip(channel)
+                    .step_by(channels)
+                    .map(|sample| f32::from(*sample) / 32768.0)
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Samples of the frame an iterator is returning, and how many of them it returned so far
+#[derive(Debug, Default)]
+pub(crate) struct FrameBuffer {
+    pub(crate) samples: Vec<i16>,
+    /// Index of the next sample to return
+    pub(crate) pos: usize,
+}
+
+impl FrameBuffer {
+    /// Returns `samples` from the first on
+    pub(crate) fn set(&mut self, samples: Vec<i16>) {
+        self.samples = samples;
+        self.pos = 0;
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.samples.clear();
+        self.pos = 0;
+    }
+
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.pos >= self.samples.len()
+    }
+
+    /// Number of samples not returned yet
+    #[cfg(feature = "rodio_source")]
+    pub(crate) fn remaining(&self) -> usize {
+        self.samples.len().saturating_sub(self.pos)
+    }
+
+    /// The samples not returned yet, which count as returned afterwards
+    pub(crate) fn take_remaining(&mut self) -> Vec<i16> {
+        let samples = self.samples.get(self.pos..).unwrap_or_default().to_vec();
+        self.pos = self.samples.len();
+
+        samples
+    }
+
+    fn pop(&mut self) -> Option<i16> {
+        let sample = *self.samples.get(self.pos)?;
+        self.pos += 1;
+
+        Some(sample)
+    }
+}
+
+/// Something that decodes a frame at a time, returned sample by sample by its `Iterator` impl
+pub(crate) trait FrameSource {
+    fn frame_buffer(&self) -> &FrameBuffer;
+
+    fn frame_buffer_mut(&mut self) -> &mut FrameBuffer;
+
+    /// Stores the next frame in the frame buffer. Returns `false` once there are no more.
+    fn load_frame(&mut self) -> bool;
+
+    fn next_sample(&mut self) -> Option<i16> {
+        while self.frame_buffer().is_exhausted() {
+            if !self.load_frame() {
+                return None;
+            }
+        }
+
+        self.frame_buffer_mut().pop()
+    }
+}
+
+/// Presentation timestamp of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp {
+    /// Raw timestamp in units of the streams time base, may be negative for encoder delay
+    pub pts: i64,
+    /// Position of the first sample since the start of the stream, counted per channel at the
+    /// output sample rate. Negative for samples presented before the start, e.g. encoder delay.
+    pub sample_position: i64,
+    /// Time of the first sample since the start of the stream, `None` if it's presented before
+    pub time: Option<Duration>,
+}
+
+impl Timestamp {
+    /// Timestamp of `pts`, relative to `start_time` unless that's `AV_NOPTS_VALUE`
+    pub(crate) fn from_pts(
+        pts: i64,
+        start_time: i64,
+        time_base: AVRational,
+        sample_rate: u32,
+    ) -> Timestamp {
+        let start_time = if start_time == AV_NOPTS_VALUE {
+            0
+        } else {
+            start_time
+        };
+
+        let sample_position = unsafe {
+            av_rescale_q(
+                pts - start_time,
+                time_base,
+                AVRational {
+                    num: 1,
+                    den: sample_rate as i32,
+                },
+            )
+        };
+
+        Timestamp::new(pts, sample_position, sample_rate)
+    }
+
+    /// Timestamp of the sample `samples` after this one
+    pub(crate) fn offset(self, samples: u64, time_base: AVRational, sample_rate: u32) -> Timestamp {
+        let pts_offset = unsafe {
+            av_rescale_q(
+                samples as i64,
+                AVRational {
+                    num: 1,
+                    den: sample_rate as i32,
+                },
+                time_base,
+            )
+        };
+
+        Timestamp::new(
+            self.pts + pts_offset,
+            self.sample_position + samples as i64,
+            sample_rate,
+        )
+    }
+
+    fn new(pts: i64, sample_position: i64, sample_rate: u32) -> Timestamp {
+        let time = if sample_position >= 0 {
+            Some(samples_to_duration(sample_position as u64, sample_rate))
+        } else {
+            None
+        };
+
+        Timestamp {
+            pts,
+            sample_position,
+            time,
+        }
+    }
+}
+
+/// How far into the stream a [`Decoder`](struct.Decoder.html) is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Position {
+    /// Number of samples per channel
+    pub samples: u64,
+    pub time: Duration,
+}
+
+impl Position {
+    pub(crate) fn new(samples: u64, sample_rate: u32) -> Position {
+        Position {
+            samples,
+            time: samples_to_duration(samples, sample_rate),
+        }
+    }
+}
+
+pub(crate) fn duration_to_samples(duration: Duration, sample_rate: u32) -> u64 {
+    (duration.as_nanos() * u128::from(sample_rate) / 1_000_000_000) as u64
+}
+
+pub(crate) fn samples_to_duration(samples: u64, sample_rate: u32) -> Duration {
+    let sample_rate = u64::from(sample_rate.max(1));
+
+    let secs = samples / sample_rate;
+    let nanos = (samples % sample_rate) * 1_000_000_000 / sample_rate;
+
+    Duration::new(secs, nanos as u32)
+}
@@ -8,6 +8,9 @@
 //! ### Features Flags
 //!
 //! - `rodio_source` to enable rodio's [`Source`](https://docs.rs/rodio/latest/rodio/source/trait.Source.html) trait
+//! - `filter` to run decoded audio through an avfilter graph
+//! - `analysis` for spectrum and spectrogram analysis, acoustic fingerprinting and tempo detection
+//! - `analysis_png` to also export spectrograms as PNG images
 //!
 //!
 //! ## Example as Rodio Source
@@ -29,11 +32,78 @@
 //!     Ok(())
 //! }
 //! ```
+mod crossfade;
+pub use crossfade::{Crossfade, CrossfadeCurve};
+
 mod decoder;
-pub use decoder::Decoder;
+pub use decoder::{Decoder, DecoderBuilder, ErrorPolicy, ErrorStats};
+
+mod demuxer;
+pub use demuxer::{scan_duration, CodecParameters, Demuxer, EncodedPacket};
+
+mod encoder;
+pub use encoder::{AudioCodec, Encoder, EncoderBuilder};
+
+#[cfg(feature = "filter")]
+mod filter;
+mod format;
+mod resample;
 
 mod error;
-pub use error::Error;
+pub use error::{AvError, Error, ErrorKind};
+
+mod frame;
+pub use frame::{AudioFrame, Position, Timestamp};
+
+mod loudness;
+pub use loudness::{measure_loudness, Loudness, LoudnessMeter};
+
+mod looping;
+pub use looping::{LoopPoints, LoopingDecoder};
+
+mod metadata;
+pub use metadata::{CoverArt, ImageFormat, Metadata};
+
+mod wav;
+pub use wav::{WavSampleFormat, WavSpec, WavWriter};
+
+mod playlist;
+pub use playlist::Playlist;
+
+mod replaygain;
+pub use replaygain::{ReplayGain, ReplayGainMode};
+
+#[cfg(feature = "analysis")]
+mod fingerprint;
+#[cfg(feature = "analysis")]
+pub use fingerprint::{fingerprint, fingerprint_with, Fingerprint, Fingerprinter};
+
+#[cfg(feature = "analysis")]
+mod spectrogram;
+#[cfg(feature = "analysis")]
+pub use spectrogram::{
+    spectrogram, spectrum, Spectrogram, SpectrogramOptions, SpectrumAnalyzer, WindowFunction,
+};
+
+#[cfg(feature = "analysis")]
+mod tempo;
+#[cfg(feature = "analysis")]
+pub use tempo::{detect_tempo, Beat, Onset, TempoAnalysis, TempoDetector};
+
+mod seek_index;
+pub use seek_index::{SeekIndex, SeekPoint};
+
+mod stream_selector;
+pub use stream_selector::{Disposition, StreamSelector};
+
+mod silence;
+pub use silence::{detect_silence, SilenceKind, SilenceOptions, SilenceRange, SilenceTrimmer};
+
+mod waveform;
+pub use waveform::{waveform, waveform_with, Waveform, WaveformBucket, WaveformMode};
+
+mod transcode;
+pub use transcode::{transcode, Progress, TranscodeOptions};
 
 #[cfg(feature = "rodio_source")]
 mod rodio;
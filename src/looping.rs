@@ -0,0 +1,192 @@
+use crate::decoder::Decoder;
+use crate::frame::{FrameBuffer, FrameSource};
+use crate::metadata::Metadata;
+
+use log::error;
+
+/// Section of a stream to repeat, in samples per channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopPoints {
+    pub start: u64,
+    /// Exclusive, `None` loops at the end of the stream
+    pub end: Option<u64>,
+}
+
+impl LoopPoints {
+    /// The whole stream
+    pub fn new() -> LoopPoints {
+        LoopPoints {
+            start: 0,
+            end: None,
+        }
+    }
+
+    /// Read from the `LOOPSTART` tag along with `LOOPLENGTH` or `LOOPEND`, all in samples.
+    /// `None` if there's no `LOOPSTART` tag.
+    pub fn from_metadata(metadata: &Metadata) -> Option<LoopPoints> {
+        let samples = |key| {
+            metadata
+                .get(key)
+                .and_then(|value| value.trim().parse::<u64>().ok())
+        };
+
+        let start = samples("LOOPSTART")?;
+        let end = match samples("LOOPLENGTH") {
+            Some(length) => Some(start + length),
+            None => samples("LOOPEND"),
+        };
+
+        Some(LoopPoints {
+            start,
+            end: end.filter(|end| *end > start),
+        })
+    }
+}
+
+impl Default for LoopPoints {
+    fn default() -> LoopPoints {
+        LoopPoints::new()
+    }
+}
+
+/// Plays a [`Decoder`](struct.Decoder.html) from the start, then repeats its loop section
+/// forever
+///
+/// Once the loop end is reached the decoder seeks back to the loop start within the same call,
+/// so playback continues without a gap and both loop points are sample accurate.
+pub struct LoopingDecoder {
+    decoder: Decoder,
+    points: LoopPoints,
+    current_frame: FrameBuffer,
+    /// Position of the sample after the current frame
+    frame_end: u64,
+    loops: u64,
+    finished: bool,
+}
+
+impl LoopingDecoder {
+    pub fn new(decoder: Decoder, points: LoopPoints) -> LoopingDecoder {
+        LoopingDecoder {
+            decoder,
+            points,
+            current_frame: FrameBuffer::default(),
+            frame_end: 0,
+            loops: 0,
+            finished: false,
+        }
+    }
+
+    /// Loop points from the tags of the file, or the whole stream if it has none
+    pub fn from_tags(decoder: Decoder) -> LoopingDecoder {
+        let points = LoopPoints::from_metadata(&decoder.metadata()).unwrap_or_default();
+
+        LoopingDecoder::new(decoder, points)
+    }
+
+    pub fn loop_points(&self) -> LoopPoints {
+        self.points
+    }
+
+    /// Number of times playback jumped back to the loop start
+    pub fn loops(&self) -> u64 {
+        self.loops
+    }
+
+    pub fn into_inner(self) -> Decoder {
+        self.decoder
+    }
+
+    /// Loads the next frame, looping back where needed. Returns `false` if decoding failed or
+    /// the loop section is empty.
+    fn load_looped_frame(&mut self) -> bool {
+        let mut looped = false;
+
+        loop {
+            let at_end = self.points.end.is_some_and(|end| self.frame_end >= end);
+
+            let frame = if at_end {
+                None
+            } else {
+                self.decoder.next_frame()
+            };
+
+            match frame {
+                Some(frame) => {
+                    // Frames without a timestamp follow the previous one
+                    let start = frame.timestamp.map_or(self.frame_end, |timestamp| {
+                        timestamp.sample_position.max(0) as u64
+                    });
+                    let num_samples = frame.num_samples() as u64;
+                    let mut samples = frame.samples;
+
+                    let keep = match self.points.end {
+                        Some(end) => end.saturating_sub(start).min(num_samples),
+                        None => num_samples,
+                    };
+                    samples.truncate(keep as usize * self.decoder._channels().max(1) as usize);
+
+                    self.frame_end = start + keep;
+
+                    if !samples.is_empty() {
+                        self.current_frame.set(samples);
+                        return true;
+                    }
+                }
+                None => {
+                    if let Some(error) = self.decoder.take_error() {
+                        error!("Stopping loop playback: {}", error);
+                        return false;
+                    }
+                    // Nothing to play between the loop points
+                    if looped {
+                        return false;
+                    }
+
+                    if let Err(error) = self.decoder._seek_samples(self.points.start) {
+                        error!("Stopping loop playback: {}", error);
+                        return false;
+                    }
+
+                    self.frame_end = self.points.start;
+                    self.loops += 1;
+                    looped = true;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn _channels(&self) -> u16 {
+        self.decoder._channels()
+    }
+
+    pub(crate) fn _sample_rate(&self) -> u32 {
+        self.decoder._sample_rate()
+    }
+}
+
+impl Iterator for LoopingDecoder {
+    type Item = i16;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_sample()
+    }
+}
+
+impl FrameSource for LoopingDecoder {
+    fn frame_buffer(&self) -> &FrameBuffer {
+        &self.current_frame
+    }
+
+    fn frame_buffer_mut(&mut self) -> &mut FrameBuffer {
+        &mut self.current_frame
+    }
+
+    fn load_frame(&mut self) -> bool {
+        if !self.finished && !self.load_looped_frame() {
+            self.finished = true;
+        }
+
+        !self.finished
+    }
+}
@@ -0,0 +1,503 @@
+use crate::decoder::Decoder;
+use crate::error::Error;
+use crate::frame::AudioFrame;
+
+use ffmpeg_sys_next::{
+    av_get_default_channel_layout, AV_CH_BACK_LEFT, AV_CH_BACK_RIGHT, AV_CH_LOW_FREQUENCY,
+    AV_CH_SIDE_LEFT, AV_CH_SIDE_RIGHT,
+};
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::path::Path;
+
+/// Gating blocks are built from 100ms segments
+const SEGMENTS_PER_SECOND: u32 = 10;
+/// 400ms momentary blocks, used for the integrated loudness
+const MOMENTARY_SEGMENTS: usize = 4;
+/// 3s short-term blocks, used for the loudness range
+const SHORT_TERM_SEGMENTS: usize = 30;
+const ABSOLUTE_GATE: f64 = -70.0;
+const INTEGRATED_RELATIVE_GATE: f64 = -10.0;
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+/// Taps per phase of the true peak interpolation filter
+const TRUE_PEAK_TAPS: usize = 12;
+
+/// Loudness of a programme per EBU R128 / ITU-R BS.1770
+///
+/// Values are `f64::NEG_INFINITY` for silence.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Gated integrated loudness in LUFS
+    pub integrated: f64,
+    /// Loudness range in LU
+    pub range: f64,
+    /// Highest absolute sample value in dBFS
+    pub sample_peak: f64,
+    /// Highest value of the signal oversampled four times below 96kHz and twice below 192kHz,
+    /// in dBTP. The interpolation filter is shorter than the BS.1770-4 reference, reading sines
+    /// up to 20kHz at most 0.12 dB high and 0.01 dB low.
+    pub true_peak: f64,
+}
+
+/// Measures loudness incrementally from interleaved signed 16bit samples
+///
+/// ```no_run
+/// use ffmpeg_decoder::{Decoder, LoudnessMeter};
+///
+/// let mut decoder = Decoder::open("input.flac")?;
+/// let mut meter = LoudnessMeter::for_decoder(&decoder);
+///
+/// while let Some(frame) = decoder.next_frame() {
+///     meter.add_frame(&frame);
+/// }
+///
+/// println!("{:.1} LUFS", meter.loudness().integrated);
+/// # Ok::<(), ffmpeg_decoder::Error>(())
+/// ```
+pub struct LoudnessMeter {
+    channels: Vec<ChannelState>,
+    segment_len: u32,
+    segment_filled: u32,
+    /// Energies of the most recent segments
+    segments: VecDeque<f64>,
+    momentary_blocks: Vec<f64>,
+    short_term_blocks: Vec<f64>,
+    sample_peak: f64,
+    true_peak: f64,
+}
+
+impl LoudnessMeter {
+    /// Channels are weighted as in ffmpegs default layout for `channels`
+    pub fn new(channels: u16, sample_rate: u32) -> LoudnessMeter {
+        let layout = unsafe { av_get_default_channel_layout(i32::from(channels)) as u64 };
+
+        LoudnessMeter::with_layout(channels, sample_rate, layout)
+    }
+
+    /// For the samples returned by `decoder`
+    pub fn for_decoder(decoder: &Decoder) -> LoudnessMeter {
+        LoudnessMeter::with_layout(
+            decoder._channels(),
+            decoder._sample_rate(),
+            decoder._channel_layout(),
+        )
+    }
+
+    fn with_layout(channels: u16, sample_rate: u32, channel_layout: u64) -> LoudnessMeter {
+        let sample_rate = sample_rate.max(1);
+        let weights = channel_weights(channels, channel_layout);
+
+        let oversampling = if sample_rate < 96_000 {
+            4
+        } else if sample_rate < 192_000 {
+            2
+        } else {
+            1
+        };
+
+        LoudnessMeter {
+            channels: weights
+                .into_iter()
+                .map(|weight| ChannelState::new(weight, f64::from(sample_rate), oversampling))
+                .collect(),
+            segment_len: (sample_rate / SEGMENTS_PER_SECOND).max(1),
+            segment_filled: 0,
+            segments: VecDeque::with_capacity(SHORT_TERM_SEGMENTS),
+            momentary_blocks: vec![],
+            short_term_blocks: vec![],
+            sample_peak: 0.0,
+            true_peak: 0.0,
+        }
+    }
+
+    /// Add a frame with the channels and sample rate the meter was created for
+    pub fn add_frame(&mut self, frame: &AudioFrame) {
+        self.add_samples(&frame.samples);
+    }
+
+    /// Add interleaved samples. The length must be a multiple of the number of channels.
+    pub fn add_samples(&mut self, samples: &[i16]) {
+        let num_channels = self.channels.len().max(1);
+
+        for frame in samples.chunks_exact(num_channels) {
+            for (channel, sample) in self.channels.iter_mut().zip(frame) {
+                let sample = f64::from(*sample) / 32768.0;
+
+                self.sample_peak = self.sample_peak.max(sample.abs());
+                self.true_peak = self.true_peak.max(channel.true_peak(sample));
+
+                let filtered = channel.k_weight(sample);
+                channel.energy += filtered * filtered;
+            }
+
+            self.segment_filled += 1;
+            if self.segment_filled == self.segment_len {
+                self.end_segment();
+            }
+        }
+    }
+
+    fn end_segment(&mut self) {
+        let segment_len = f64::from(self.segment_len);
+
+        let energy = self
+            .channels
+            .iter_mut()
+            .map(|channel| {
+                let energy = channel.weight * channel.energy / segment_len;
+                channel.energy = 0.0;
+                energy
+            })
+            .sum();
+
+        self.segment_filled = 0;
+
+        if self.segments.len() == SHORT_TERM_SEGMENTS {
+            self.segments.pop_front();
+        }
+        self.segments.push_back(energy);
+
+        if self.segments.len() >= MOMENTARY_SEGMENTS {
+            self.momentary_blocks
+                .push(mean(self.segments.iter().rev().take(MOMENTARY_SEGMENTS)));
+        }
+        if self.segments.len() == SHORT_TERM_SEGMENTS {
+            self.short_term_blocks.push(mean(self.segments.iter()));
+        }
+    }
+
+    /// Loudness of all samples added so far
+    pub fn loudness(&self) -> Loudness {
+        Loudness {
+            integrated: integrated_loudness(&self.momentary_blocks),
+            range: loudness_range(&self.short_term_blocks),
+            sample_peak: to_db(self.sample_peak),
+            true_peak: to_db(self.true_peak.max(self.sample_peak)),
+        }
+    }
+}
+
+/// Decode the file and measure its loudness
+pub fn measure_loudness(path: impl AsRef<Path>) -> Result<Loudness, Error> {
+    let mut decoder = Decoder::open(path)?;
+    let mut meter = LoudnessMeter::for_decoder(&decoder);
+
+    while let Some(frame) = decoder.next_frame() {
+        meter.add_frame(&frame);
+    }
+
+    match decoder.take_error() {
+        Some(error) => Err(error),
+        None => Ok(meter.loudness()),
+    }
+}
+
+struct ChannelState {
+    weight: f64,
+    shelf: Biquad,
+    high_pass: Biquad,
+    /// Sum of squared K-weighted samples of the current segment
+    energy: f64,
+    /// Interpolation filter per oversampled phase, and the most recent samples
+    phases: Vec<[f64; TRUE_PEAK_TAPS]>,
+    history: [f64; TRUE_PEAK_TAPS],
+}
+
+impl ChannelState {
+    fn new(weight: f64, sample_rate: f64, oversampling: usize) -> ChannelState {
+        ChannelState {
+            weight,
+            shelf: Biquad::high_shelf(sample_rate),
+            high_pass: Biquad::high_pass(sample_rate),
+            energy: 0.0,
+            phases: interpolation_phases(oversampling),
+            history: [0.0; TRUE_PEAK_TAPS],
+        }
+    }
+
+    fn k_weight(&mut self, sample: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(sample))
+    }
+
+    /// Peak of the points interpolated between two of the recent samples
+    fn true_peak(&mut self, sample: f64) -> f64 {
+        self.history.copy_within(1.., 0);
+        self.history[TRUE_PEAK_TAPS - 1] = sample;
+
+        self.phases
+            .iter()
+            .map(|taps| {
+                taps.iter()
+                    .zip(self.history.iter().rev())
+                    .map(|(tap, sample)| tap * sample)
+                    .sum::<f64>()
+                    .abs()
+            })
+            .fold(0.0, f64::max)
+    }
+}
+
+/// Second order IIR filter, transposed direct form II
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    /// First stage of the K-weighting, modelling the acoustic effect of the head. Coefficients
+    /// are derived for any rate as in libebur128, matching BS.1770 at 48kHz.
+    fn high_shelf(sample_rate: f64) -> Biquad {
+        let f0 = 1_681.974_450_955_533;
+        let gain = 3.999_843_853_973_347;
+        let q = 0.707_175_236_955_419_6;
+
+        let k = (PI * f0 / sample_rate).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.499_666_774_154_541_6);
+        let a0 = 1.0 + k / q + k * k;
+
+        Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [0.0; 2],
+        }
+    }
+
+    /// Second stage of the K-weighting, the RLB high pass
+    fn high_pass(sample_rate: f64) -> Biquad {
+        let f0 = 38.135_470_876_024_44;
+        let q = 0.500_327_037_323_877_3;
+
+        let k = (PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+
+        Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// Hann windowed sinc filter, split into one set of taps per oversampled phase
+fn interpolation_phases(oversampling: usize) -> Vec<[f64; TRUE_PEAK_TAPS]> {
+    let half = (TRUE_PEAK_TAPS / 2) as f64;
+
+    (1..oversampling)
+        .map(|phase| {
+            let mut taps = [0.0; TRUE_PEAK_TAPS];
+
+            for (k, tap) in taps.iter_mut().enumerate() {
+                // Distance of the interpolated point from the sample at `k`
+                let x = k as f64 - half + phase as f64 / oversampling as f64;
+                let window = 0.5 * (1.0 + (PI * x / half).cos());
+
+                *tap = window * sinc(x);
+            }
+
+            taps
+        })
+        .collect()
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Weights of BS.1770, surround channels count 1.41 times and LFE is ignored
+fn channel_weights(channels: u16, channel_layout: u64) -> Vec<f64> {
+    let mut weights = vec![1.0; channels as usize];
+
+    if (channel_layout.count_ones() as u16) != channels {
+        return weights;
+    }
+
+    let positions = (0..64)
+        .map(|bit| 1u64 << bit)
+        .filter(|position| channel_layout & position != 0);
+
+    for (weight, position) in weights.iter_mut().zip(positions) {
+        *weight = match position {
+            AV_CH_LOW_FREQUENCY => 0.0,
+            AV_CH_BACK_LEFT | AV_CH_BACK_RIGHT | AV_CH_SIDE_LEFT | AV_CH_SIDE_RIGHT => 1.41,
+            _ => 1.0,
+        };
+    }
+
+    weights
+}
+
+fn integrated_loudness(blocks: &[f64]) -> f64 {
+    let gated = gate(blocks, INTEGRATED_RELATIVE_GATE);
+
+    energy_to_loudness(mean(gated.iter()))
+}
+
+/// Difference between the 10th and 95th percentile of the gated short-term loudness
+fn loudness_range(blocks: &[f64]) -> f64 {
+    let mut loudness = gate(blocks, RANGE_RELATIVE_GATE)
+        .into_iter()
+        .map(energy_to_loudness)
+        .collect::<Vec<_>>();
+
+    if loudness.is_empty() {
+        return 0.0;
+    }
+
+    loudness.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let percentile = |p: f64| loudness[((loudness.len() - 1) as f64 * p).round() as usize];
+
+    percentile(0.95) - percentile(0.10)
+}
+
+/// Blocks above the absolute gate and `relative_gate` LU below their mean loudness
+fn gate(blocks: &[f64], relative_gate: f64) -> Vec<f64> {
+    let absolute = blocks
+        .iter()
+        .copied()
+        .filter(|energy| energy_to_loudness(*energy) > ABSOLUTE_GATE)
+        .collect::<Vec<_>>();
+
+    let relative = energy_to_loudness(mean(absolute.iter())) + relative_gate;
+
+    absolute
+        .into_iter()
+        .filter(|energy| energy_to_loudness(*energy) > relative)
+        .collect()
+}
+
+fn mean<'a>(values: impl Iterator<Item = &'a f64>) -> f64 {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+
+    if count == 0 {
+        0.0
+    } else {
+        sum / f64::from(count)
+    }
+}
+
+fn energy_to_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn to_db(value: f64) -> f64 {
+    20.0 * value.log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Energy of a block of `loudness` LUFS
+    fn energy(loudness: f64) -> f64 {
+        10f64.powf((loudness + 0.691) / 10.0)
+    }
+
+    /// Blocks of each `(loudness, seconds)` part, one per 100ms step as the meter makes them
+    fn blocks(parts: &[(f64, usize)]) -> Vec<f64> {
+        parts
+            .iter()
+            .flat_map(|&(loudness, seconds)| vec![energy(loudness); seconds * 10])
+            .collect()
+    }
+
+    #[test]
+    fn k_weighting_coefficients_at_48k() {
+        // ITU-R BS.1770-4, tables 1 and 2
+        let shelf = Biquad::high_shelf(48_000.0);
+        let expected_b = [
+            1.535_124_859_586_97,
+            -2.691_696_189_406_38,
+            1.198_392_810_852_85,
+        ];
+        let expected_a = [-1.690_659_293_182_41, 0.732_480_774_215_85];
+        for (value, expected) in shelf
+            .b
+            .iter()
+            .chain(&shelf.a)
+            .zip(expected_b.iter().chain(&expected_a))
+        {
+            assert!((value - expected).abs() < 1e-6, "{} != {}", value, expected);
+        }
+
+        let high_pass = Biquad::high_pass(48_000.0);
+        assert_eq!(high_pass.b, [1.0, -2.0, 1.0]);
+        let expected_a = [-1.990_047_454_833_98, 0.990_072_250_366_21];
+        for (value, expected) in high_pass.a.iter().zip(&expected_a) {
+            assert!((value - expected).abs() < 1e-6, "{} != {}", value, expected);
+        }
+    }
+
+    #[test]
+    fn gating_tech_3341() {
+        // EBU Tech 3341 test cases 3 and 4, both -23.0 ±0.1 LUFS
+        let case_3 = blocks(&[(-36.0, 10), (-23.0, 60), (-36.0, 10)]);
+        assert!((integrated_loudness(&case_3) + 23.0).abs() < 0.1);
+
+        let case_4 = blocks(&[
+            (-72.0, 10),
+            (-36.0, 10),
+            (-23.0, 60),
+            (-36.0, 10),
+            (-72.0, 10),
+        ]);
+        assert!((integrated_loudness(&case_4) + 23.0).abs() < 0.1);
+
+        // Below the absolute gate throughout
+        assert_eq!(
+            integrated_loudness(&blocks(&[(-75.0, 10)])),
+            f64::NEG_INFINITY
+        );
+    }
+
+    #[test]
+    fn loudness_range_tech_3342() {
+        // EBU Tech 3342 test case 1, 10 ±1 LU
+        let case_1 = blocks(&[(-20.0, 20), (-30.0, 20)]);
+        assert!((loudness_range(&case_1) - 10.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn sine_at_minus_23_dbfs() {
+        // EBU Tech 3341 test case 1, shortened: 1kHz at -23 dBFS in both channels
+        let sample_rate = 48_000;
+        let amplitude = 10f64.powf(-23.0 / 20.0) * 32768.0;
+
+        let samples = (0..sample_rate * 5)
+            .flat_map(|n| {
+                let phase = 2.0 * PI * 1000.0 * f64::from(n) / f64::from(sample_rate);
+                let sample = (amplitude * phase.sin()).round() as i16;
+                vec![sample, sample]
+            })
+            .collect::<Vec<_>>();
+
+        let mut meter = LoudnessMeter::with_layout(2, sample_rate, 0b11);
+        meter.add_samples(&samples);
+        let loudness = meter.loudness();
+
+        assert!(
+            (loudness.integrated + 23.0).abs() < 0.1,
+            "{}",
+            loudness.integrated
+        );
+        assert!((loudness.sample_peak + 23.0).abs() < 0.01);
+        assert!((loudness.true_peak + 23.0).abs() < 0.12);
+    }
+}
@@ -0,0 +1,150 @@
+use crate::format::FormatContext;
+
+use ffmpeg_sys_next::{
+    av_dict_get, av_dict_set, AVCodecID, AVDictionary, AVDictionaryEntry, AV_DICT_IGNORE_SUFFIX,
+    AV_DISPOSITION_ATTACHED_PIC,
+};
+use std::ffi::{CStr, CString};
+use std::ptr;
+use std::slice;
+
+/// Tags of a file, e.g. `title`, `artist` or `REPLAYGAIN_TRACK_GAIN`
+///
+/// Keys are kept as stored in the file, lookups ignore case.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    entries: Vec<(String, String)>,
+}
+
+impl Metadata {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        let key = key.into();
+        let value = value.into();
+
+        match self
+            .entries
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case(&key))
+        {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((key, value)),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Add the entries of `other` whose keys aren't present yet
+    pub(crate) fn merge(&mut self, other: Metadata) {
+        for (key, value) in other.entries {
+            if self.get(&key).is_none() {
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub(crate) fn from_dictionary(dict: *const AVDictionary) -> Metadata {
+        let mut metadata = Metadata::default();
+
+        let empty = CString::default();
+        let mut entry = ptr::null::<AVDictionaryEntry>();
+        loop {
+            entry = unsafe { av_dict_get(dict, empty.as_ptr(), entry, AV_DICT_IGNORE_SUFFIX) };
+
+            let (key, value) = match unsafe { entry.as_ref() } {
+                Some(entry) => unsafe { (CStr::from_ptr(entry.key), CStr::from_ptr(entry.value)) },
+                None => break,
+            };
+
+            metadata.entries.push((
+                key.to_string_lossy().into_owned(),
+                value.to_string_lossy().into_owned(),
+            ));
+        }
+
+        metadata
+    }
+
+    /// Set all entries on `dict`, allocating it if null
+    pub(crate) fn write_dictionary(&self, dict: *mut *mut AVDictionary) {
+        for (key, value) in &self.entries {
+            // Tags with interior nul bytes can't be represented
+            let (key, value) = match (CString::new(key.as_str()), CString::new(value.as_str())) {
+                (Ok(key), Ok(value)) => (key, value),
+                _ => continue,
+            };
+
+            unsafe { av_dict_set(dict, key.as_ptr(), value.as_ptr(), 0) };
+        }
+    }
+}
+
+/// Image formats ffmpeg can store as embedded cover art
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+}
+
+impl ImageFormat {
+    fn from_codec_id(codec_id: AVCodecID) -> Option<ImageFormat> {
+        match codec_id {
+            AVCodecID::AV_CODEC_ID_MJPEG => Some(ImageFormat::Jpeg),
+            AVCodecID::AV_CODEC_ID_PNG => Some(ImageFormat::Png),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn codec_id(self) -> AVCodecID {
+        match self {
+            ImageFormat::Jpeg => AVCodecID::AV_CODEC_ID_MJPEG,
+            ImageFormat::Png => AVCodecID::AV_CODEC_ID_PNG,
+        }
+    }
+}
+
+/// Picture embedded in a file, e.g. an album cover
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverArt {
+    /// The encoded image file
+    pub data: Vec<u8>,
+    pub format: ImageFormat,
+}
+
+impl CoverArt {
+    /// The first attached picture stream in a format ffmpeg can write back out
+    pub(crate) fn find(format_ctx: &FormatContext) -> Option<CoverArt> {
+        format_ctx.streams().iter().find_map(|stream| {
+            let stream = unsafe { stream.as_ref().unwrap() };
+            if stream.disposition & AV_DISPOSITION_ATTACHED_PIC == 0 {
+                return None;
+            }
+
+            let codec_id = unsafe { stream.codecpar.as_ref().unwrap().codec_id };
+            let format = ImageFormat::from_codec_id(codec_id)?;
+
+            let packet = &stream.attached_pic;
+            if packet.data.is_null() || packet.size <= 0 {
+                return None;
+            }
+
+            let data = unsafe { slice::from_raw_parts(packet.data, packet.size as usize) }.to_vec();
+
+            Some(CoverArt { data, format })
+        })
+    }
+}
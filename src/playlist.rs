@@ -0,0 +1,264 @@
+use crate::decoder::Decoder;
+use crate::error::Error;
+use crate::frame::{FrameBuffer, FrameSource};
+use crate::resample::{AudioFormat, Resampler};
+
+use ffmpeg_sys_next::AVSampleFormat;
+use log::error;
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+enum Entry {
+    Path(PathBuf),
+    Decoder(Box<Decoder>),
+}
+
+/// An opened track, converted to a fixed channel count and sample rate
+pub(crate) struct Track {
+    decoder: Decoder,
+    resampler: Option<Resampler>,
+    flushed: bool,
+}
+
+impl Track {
+    pub(crate) fn new(decoder: Decoder, channels: u16, sample_rate: u32) -> Result<Track, Error> {
+        let format = |channel_layout, channels: u16, sample_rate: u32| {
+            AudioFormat::new(
+                channel_layout,
+                i32::from(channels),
+                AVSampleFormat::AV_SAMPLE_FMT_S16,
+                sample_rate as i32,
+            )
+        };
+
+        let input = format(
+            decoder._channel_layout(),
+            decoder._channels(),
+            decoder._sample_rate(),
+        );
+        let output = format(0, channels, sample_rate);
+
+        let resampler = if input != output {
+            Some(Resampler::new(input, output)?)
+        } else {
+            None
+        };
+
+        Ok(Track {
+            decoder,
+            resampler,
+            flushed: false,
+        })
+    }
+
+    /// The next converted samples, `None` at the end of the track
+    pub(crate) fn next_samples(&mut self) -> Option<Result<Vec<i16>, Error>> {
+        while let Some(frame) = self.decoder.next_frame() {
+            let samples = match &mut self.resampler {
+                Some(resampler) => match resampler.convert(&frame.samples) {
+                    Ok(samples) => samples,
+                    Err(error) => return Some(Err(error)),
+                },
+                None => frame.samples,
+            };
+
+            if !samples.is_empty() {
+                return Some(Ok(samples));
+            }
+        }
+
+        if let Some(error) = self.decoder.take_error() {
+            return Some(Err(error));
+        }
+
+        // The resampler holds back a few samples, which belong before the next track
+        match &mut self.resampler {
+            Some(resampler) if !self.flushed => {
+                self.flushed = true;
+                Some(resampler.flush())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Plays a queue of tracks one after the other, as a single stream without gaps
+///
+/// Tracks with another sample rate or channel layout than the playlist are converted. The
+/// playlist takes its format from the first track, unless created
+/// [`with_format`](#method.with_format). Tracks are played exactly as decoded, with the encoder
+/// delay and padding trimmed where the file records them, so albums mastered without pauses
+/// play without gaps.
+///
+/// The next track is opened as soon as the previous one starts playing. Tracks that fail to
+/// open or decode are skipped, and their errors kept for [`take_errors`](#method.take_errors).
+pub struct Playlist {
+    /// Channels and sample rate of the returned samples
+    format: Option<(u16, u32)>,
+    queue: VecDeque<Entry>,
+    current: Option<Track>,
+    next: Option<Track>,
+    current_frame: FrameBuffer,
+    errors: Vec<Error>,
+}
+
+impl Playlist {
+    /// Empty playlist in the format of the first track pushed
+    pub fn new() -> Playlist {
+        Playlist {
+            format: None,
+            queue: VecDeque::new(),
+            current: None,
+            next: None,
+            current_frame: FrameBuffer::default(),
+            errors: vec![],
+        }
+    }
+
+    /// Empty playlist converting all tracks to `channels` and `sample_rate`
+    pub fn with_format(channels: u16, sample_rate: u32) -> Playlist {
+        Playlist {
+            format: Some((channels.max(1), sample_rate.max(1))),
+            ..Playlist::new()
+        }
+    }
+
+    /// Queue the file at `path`, opened shortly before it plays
+    pub fn push(&mut self, path: impl AsRef<Path>) {
+        self.queue
+            .push_back(Entry::Path(path.as_ref().to_path_buf()));
+        self.open_format_track();
+    }
+
+    /// Queue an opened decoder, played from its current position
+    pub fn push_decoder(&mut self, decoder: Decoder) {
+        self.queue.push_back(Entry::Decoder(Box::new(decoder)));
+        self.open_format_track();
+    }
+
+    /// Number of tracks not started yet
+    pub fn len(&self) -> usize {
+        self.queue.len() + self.next.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Errors of the tracks skipped since the last call
+    pub fn take_errors(&mut self) -> Vec<Error> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Without a format yet, open the first track right away to take it from
+    fn open_format_track(&mut self) {
+        if self.format.is_none() && self.next.is_none() {
+            self.next = self.open_next();
+        }
+    }
+
+    /// Opens the first track of the queue that can be played
+    fn open_next(&mut self) -> Option<Track> {
+        while let Some(entry) = self.queue.pop_front() {
+            let decoder = match entry {
+                Entry::Path(path) => match Decoder::open(&path) {
+                    Ok(decoder) => decoder,
+                    Err(error) => {
+                        self.skip_track(error);
+                        continue;
+                    }
+                },
+                Entry::Decoder(decoder) => *decoder,
+            };
+
+            let (channels, sample_rate) = *self
+                .format
+                .get_or_insert((decoder._channels().max(1), decoder._sample_rate().max(1)));
+
+            match Track::new(decoder, channels, sample_rate) {
+                Ok(track) => return Some(track),
+                Err(error) => self.skip_track(error),
+            }
+        }
+
+        None
+    }
+
+    fn skip_track(&mut self, error: Error) {
+        error!("Skipping track: {}", error);
+        self.errors.push(error);
+    }
+
+    /// Loads the next samples, moving on to the next track where needed. Returns `false` once
+    /// all tracks have played.
+    fn load_track_samples(&mut self) -> bool {
+        loop {
+            if self.current.is_none() {
+                self.current = match self.next.take() {
+                    Some(track) => Some(track),
+                    None => self.open_next(),
+                };
+                if self.current.is_none() {
+                    return false;
+                }
+
+                self.next = self.open_next();
+            }
+
+            let track = self.current.as_mut().unwrap();
+            match track.next_samples() {
+                Some(Ok(samples)) => {
+                    if !samples.is_empty() {
+                        self.current_frame.set(samples);
+                        return true;
+                    }
+                }
+                Some(Err(error)) => {
+                    self.skip_track(error);
+                    self.current = None;
+                }
+                None => self.current = None,
+            }
+        }
+    }
+
+    pub(crate) fn _channels(&self) -> u16 {
+        self.format.map_or(1, |(channels, _)| channels)
+    }
+
+    pub(crate) fn _sample_rate(&self) -> u32 {
+        self.format.map_or(44100, |(_, sample_rate)| sample_rate)
+    }
+}
+
+impl Default for Playlist {
+    fn default() -> Playlist {
+        Playlist::new()
+    }
+}
+
+unsafe impl Send for Playlist {}
+
+impl Iterator for Playlist {
+    type Item = i16;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_sample()
+    }
+}
+
+impl FrameSource for Playlist {
+    fn frame_buffer(&self) -> &FrameBuffer {
+        &self.current_frame
+    }
+
+    fn frame_buffer_mut(&mut self) -> &mut FrameBuffer {
+        &mut self.current_frame
+    }
+
+    fn load_frame(&mut self) -> bool {
+        self.load_track_samples()
+    }
+}
@@ -0,0 +1,173 @@
+use crate::metadata::Metadata;
+
+/// R128 gains in Opus files target -23 LUFS, 5 dB below the ReplayGain reference level
+const R128_TO_REPLAYGAIN: f64 = 5.0;
+
+/// Which gain a [`Decoder`](struct.Decoder.html) applies, see
+/// [`DecoderBuilder::replay_gain`](struct.DecoderBuilder.html#method.replay_gain)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayGainMode {
+    /// Same loudness for every track, falls back to the album gain if missing
+    Track,
+    /// Same loudness for every album keeping the differences within it, falls back to the
+    /// track gain if missing
+    Album,
+}
+
+/// ReplayGain values from the tags of a file, gains in dB relative to the ReplayGain reference
+/// level and peaks as linear amplitude
+///
+/// Read from `REPLAYGAIN_TRACK_GAIN`, `REPLAYGAIN_ALBUM_GAIN` and the matching `_PEAK` tags,
+/// or the `R128_TRACK_GAIN` and `R128_ALBUM_GAIN` tags of Opus files.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f64>,
+    pub track_peak: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>,
+}
+
+impl ReplayGain {
+    pub fn from_metadata(metadata: &Metadata) -> ReplayGain {
+        let gain = |replaygain_key, r128_key| {
+            metadata
+                .get(replaygain_key)
+                .and_then(parse_gain)
+                .or_else(|| metadata.get(r128_key).and_then(parse_r128_gain))
+        };
+        let peak = |key| metadata.get(key).and_then(|peak| peak.trim().parse().ok());
+
+        ReplayGain {
+            track_gain: gain("REPLAYGAIN_TRACK_GAIN", "R128_TRACK_GAIN"),
+            track_peak: peak("REPLAYGAIN_TRACK_PEAK"),
+            album_gain: gain("REPLAYGAIN_ALBUM_GAIN", "R128_ALBUM_GAIN"),
+            album_peak: peak("REPLAYGAIN_ALBUM_PEAK"),
+        }
+    }
+
+    /// Linear factor to scale samples by, `None` if the file has no gain tags. Lowered so the
+    /// tagged peak doesn't clip.
+    pub fn scale(&self, mode: ReplayGainMode) -> Option<f64> {
+        let track = self.track_gain.map(|gain| (gain, self.track_peak));
+        let album = self.album_gain.map(|gain| (gain, self.album_peak));
+
+        let (gain, peak) = match mode {
+            ReplayGainMode::Track => track.or(album),
+            ReplayGainMode::Album => album.or(track),
+        }?;
+
+        let scale = 10f64.powf(gain / 20.0);
+
+        Some(match peak.filter(|peak| *peak > 0.0) {
+            Some(peak) => scale.min(1.0 / peak),
+            None => scale,
+        })
+    }
+}
+
+/// Scale samples in place, saturating those that would still clip
+pub(crate) fn apply_gain(samples: &mut [i16], scale: f64) {
+    for sample in samples {
+        *sample = (f64::from(*sample) * scale)
+            .round()
+            .clamp(f64::from(i16::MIN), f64::from(i16::MAX)) as i16;
+    }
+}
+
+/// e.g. `-6.20 dB`
+fn parse_gain(value: &str) -> Option<f64> {
+    let value = value.trim().to_ascii_lowercase();
+
+    value.trim_end_matches("db").trim().parse().ok()
+}
+
+/// Q7.8 fixed point dB, e.g. `-1536` for -6 dB
+fn parse_r128_gain(value: &str) -> Option<f64> {
+    let gain = value.trim().parse::<i16>().ok()?;
+
+    Some(f64::from(gain) / 256.0 + R128_TO_REPLAYGAIN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(tags: &[(&str, &str)]) -> Metadata {
+        let mut metadata = Metadata::default();
+        for (key, value) in tags {
+            metadata.insert(*key, *value);
+        }
+
+        metadata
+    }
+
+    #[test]
+    fn replaygain_tags() {
+        let gain = ReplayGain::from_metadata(&metadata(&[
+            ("REPLAYGAIN_TRACK_GAIN", "-6.20 dB"),
+            ("replaygain_track_peak", "0.988553"),
+            ("REPLAYGAIN_ALBUM_GAIN", " +1.5dB "),
+            ("REPLAYGAIN_ALBUM_PEAK", "1.2"),
+        ]));
+
+        assert_eq!(
+            gain,
+            ReplayGain {
+                track_gain: Some(-6.2),
+                track_peak: Some(0.988553),
+                album_gain: Some(1.5),
+                album_peak: Some(1.2),
+            }
+        );
+    }
+
+    #[test]
+    fn r128_tags() {
+        let gain = ReplayGain::from_metadata(&metadata(&[
+            ("R128_TRACK_GAIN", "-1536"),
+            ("R128_ALBUM_GAIN", "384"),
+        ]));
+
+        assert_eq!(gain.track_gain, Some(-1.0));
+        assert_eq!(gain.album_gain, Some(6.5));
+        assert_eq!(gain.track_peak, None);
+    }
+
+    #[test]
+    fn replaygain_tags_take_precedence_and_invalid_are_ignored() {
+        let gain = ReplayGain::from_metadata(&metadata(&[
+            ("R128_TRACK_GAIN", "-1536"),
+            ("REPLAYGAIN_TRACK_GAIN", "-3 dB"),
+            ("R128_ALBUM_GAIN", "40000"),
+            ("REPLAYGAIN_TRACK_PEAK", "loud"),
+        ]));
+
+        assert_eq!(gain.track_gain, Some(-3.0));
+        assert_eq!(gain.album_gain, None);
+        assert_eq!(gain.track_peak, None);
+        assert_eq!(
+            ReplayGain::from_metadata(&Metadata::default()),
+            ReplayGain::default()
+        );
+    }
+
+    #[test]
+    fn scale_falls_back_and_avoids_clipping() {
+        let gain = ReplayGain {
+            track_gain: Some(-20.0),
+            track_peak: None,
+            album_gain: Some(6.0),
+            album_peak: Some(0.8),
+        };
+
+        assert!((gain.scale(ReplayGainMode::Track).unwrap() - 0.1).abs() < 1e-12);
+        assert!((gain.scale(ReplayGainMode::Album).unwrap() - 1.25).abs() < 1e-12);
+
+        let track_only = ReplayGain {
+            album_gain: None,
+            ..gain
+        };
+        assert!((track_only.scale(ReplayGainMode::Album).unwrap() - 0.1).abs() < 1e-12);
+        assert_eq!(ReplayGain::default().scale(ReplayGainMode::Track), None);
+    }
+}
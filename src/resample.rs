@@ -0,0 +1,149 @@
+use crate::error::{AvError, Error};
+
+use ffmpeg_sys_next::{
+    av_get_channel_layout_nb_channels, av_get_default_channel_layout, swr_alloc_set_opts,
+    swr_convert, swr_free, swr_get_out_samples, swr_init, AVSampleFormat,
+};
+use std::ptr;
+
+/// Layout, sample format and rate of one side of a conversion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct AudioFormat {
+    pub(crate) channel_layout: u64,
+    pub(crate) sample_format: AVSampleFormat,
+    pub(crate) sample_rate: i32,
+}
+
+impl AudioFormat {
+    /// Falls back to the default layout for `channels` if `channel_layout` is unknown (0)
+    pub(crate) fn new(
+        channel_layout: u64,
+        channels: i32,
+        sample_format: AVSampleFormat,
+        sample_rate: i32,
+    ) -> AudioFormat {
+        let channel_layout = if channel_layout == 0 {
+            unsafe { av_get_default_channel_layout(channels) as u64 }
+        } else {
+            channel_layout
+        };
+
+        AudioFormat {
+            channel_layout,
+            sample_format,
+            sample_rate,
+        }
+    }
+
+    pub(crate) fn channels(&self) -> i32 {
+        unsafe { av_get_channel_layout_nb_channels(self.channel_layout) }
+    }
+}
+
+pub(crate) struct SwrContext {
+    pub(crate) inner: *mut ffmpeg_sys_next::SwrContext,
+}
+
+impl SwrContext {
+    pub(crate) fn new(input: AudioFormat, output: AudioFormat) -> Result<SwrContext, Error> {
+        let swr_ctx: *mut ffmpeg_sys_next::SwrContext = unsafe {
+            swr_alloc_set_opts(
+                ptr::null_mut(),
+                output.channel_layout as i64,
+                output.sample_format,
+                output.sample_rate,
+                input.channel_layout as i64,
+                input.sample_format,
+                input.sample_rate,
+                0,
+                ptr::null_mut(),
+            )
+        };
+
+        // Freed on drop, including when initialization fails
+        let swr_ctx = SwrContext { inner: swr_ctx };
+
+        let status = unsafe { swr_init(swr_ctx.inner) };
+        if status != 0 {
+            return Err(Error::InitializeSwr(AvError::new(status)));
+        }
+
+        Ok(swr_ctx)
+    }
+}
+
+impl Drop for SwrContext {
+    fn drop(&mut self) {
+        unsafe { swr_free(&mut self.inner) };
+    }
+}
+
+/// Converts interleaved signed 16bit samples to another rate and layout, for analysis and
+/// playback that expect a fixed format
+pub(crate) struct Resampler {
+    swr_ctx: SwrContext,
+    input: AudioFormat,
+    output: AudioFormat,
+}
+
+impl Resampler {
+    pub(crate) fn new(input: AudioFormat, output: AudioFormat) -> Result<Resampler, Error> {
+        let input = AudioFormat {
+            sample_format: AVSampleFormat::AV_SAMPLE_FMT_S16,
+            ..input
+        };
+        let output = AudioFormat {
+            sample_format: AVSampleFormat::AV_SAMPLE_FMT_S16,
+            ..output
+        };
+
+        Ok(Resampler {
+            swr_ctx: SwrContext::new(input, output)?,
+            input,
+            output,
+        })
+    }
+
+    /// Convert `samples`, returning what the resampler has ready. The length must be a multiple
+    /// of the number of input channels.
+    pub(crate) fn convert(&mut self, samples: &[i16]) -> Result<Vec<i16>, Error> {
+        let num_samples = samples.len() / self.input.channels().max(1) as usize;
+
+        let mut input = [samples.as_ptr() as *const u8];
+        self.convert_raw(input.as_mut_ptr(), num_samples as i32)
+    }
+
+    /// Samples still buffered in the resampler, at the end of the input
+    pub(crate) fn flush(&mut self) -> Result<Vec<i16>, Error> {
+        self.convert_raw(ptr::null_mut(), 0)
+    }
+
+    fn convert_raw(&mut self, input: *mut *const u8, num_samples: i32) -> Result<Vec<i16>, Error> {
+        let channels = self.output.channels().max(1) as usize;
+
+        let capacity = unsafe { swr_get_out_samples(self.swr_ctx.inner, num_samples) };
+        if capacity < 0 {
+            return Err(Error::ConvertSamples(AvError::new(capacity)));
+        }
+
+        let mut output = vec![0i16; capacity as usize * channels];
+        let mut planes = [output.as_mut_ptr() as *mut u8];
+
+        let converted = unsafe {
+            swr_convert(
+                self.swr_ctx.inner,
+                planes.as_mut_ptr(),
+                capacity,
+                input,
+                num_samples,
+            )
+        };
+        if converted < 0 {
+            return Err(Error::ConvertSamples(AvError::new(converted)));
+        }
+
+        output.truncate(converted as usize * channels);
+
+        Ok(output)
+    }
+}
@@ -1,27 +1,41 @@
-use crate::Decoder;
+use crate::frame::FrameSource;
+use crate::{Crossfade, Decoder, LoopingDecoder, Playlist, SilenceTrimmer};
 
 use rodio::source::Source;
 
 use std::time::Duration;
 
-impl Source for Decoder {
-    #[inline]
-    fn current_frame_len(&self) -> Option<usize> {
-        self._current_frame_len()
-    }
+/// Implements `Source` for a `FrameSource`, `total_duration` being the given expression of
+/// `$source`
+macro_rules! impl_source {
+    ($type:ty, |$source:ident| $total_duration:expr) => {
+        impl Source for $type {
+            #[inline]
+            fn current_frame_len(&self) -> Option<usize> {
+                Some(self.frame_buffer().remaining())
+            }
 
-    #[inline]
-    fn channels(&self) -> u16 {
-        self._channels()
-    }
+            #[inline]
+            fn channels(&self) -> u16 {
+                self._channels()
+            }
 
-    #[inline]
-    fn sample_rate(&self) -> u32 {
-        self._sample_rate()
-    }
+            #[inline]
+            fn sample_rate(&self) -> u32 {
+                self._sample_rate()
+            }
 
-    #[inline]
-    fn total_duration(&self) -> Option<Duration> {
-        self._total_duration()
-    }
+            #[inline]
+            fn total_duration(&self) -> Option<Duration> {
+                let $source = self;
+                $total_duration
+            }
+        }
+    };
 }
+
+impl_source!(Decoder, |decoder| decoder._total_duration());
+impl_source!(SilenceTrimmer, |_trimmer| None);
+impl_source!(LoopingDecoder, |_looping| None);
+impl_source!(Playlist, |_playlist| None);
+impl_source!(Crossfade, |crossfade| crossfade.duration());
@@ -0,0 +1,263 @@
+use crate::demuxer::Demuxer;
+use crate::error::Error;
+use crate::format::Stream;
+use crate::frame::duration_to_samples;
+use crate::stream_selector::StreamSelector;
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+const MAGIC: &[u8; 4] = b"FDSI";
+const VERSION: u8 = 2;
+
+/// Default spacing of the points of a [`SeekIndex`](struct.SeekIndex.html)
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A packet to start decoding from when seeking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeekPoint {
+    /// Position of the first sample of the packet, counted per channel
+    pub sample: u64,
+    /// Byte offset of the packet in the file
+    pub offset: u64,
+}
+
+/// Byte offsets of packets of an audio stream of a file, for exact seeking in files without an
+/// index of their own, such as VBR MP3 and ADTS
+///
+/// Built by reading the file once without decoding it, and can be saved alongside the file to
+/// skip that next time. Used by a [`Decoder`](struct.Decoder.html) opened with
+/// [`DecoderBuilder::seek_index`](struct.DecoderBuilder.html#method.seek_index).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeekIndex {
+    /// Index of the stream in the file
+    pub stream_index: usize,
+    pub sample_rate: u32,
+    /// Sorted by position
+    pub points: Vec<SeekPoint>,
+}
+
+impl SeekIndex {
+    /// Index the first audio stream of the file with a point every 500ms
+    pub fn build(path: impl AsRef<Path>) -> Result<SeekIndex, Error> {
+        SeekIndex::build_with(path, &StreamSelector::new(), DEFAULT_INTERVAL)
+    }
+
+    /// Index the audio stream picked by `stream` with points at least `interval` apart. Pass
+    /// the selector the decoder is opened with. Shorter intervals make seeks faster, as less is
+    /// decoded and discarded after each, but the index larger.
+    pub fn build_with(
+        path: impl AsRef<Path>,
+        stream: &StreamSelector,
+        interval: Duration,
+    ) -> Result<SeekIndex, Error> {
+        let mut demuxer = Demuxer::open_selected(path, stream)?;
+        let stream_index = demuxer.stream_index();
+        let sample_rate = demuxer.sample_rate();
+        let interval = duration_to_samples(interval, sample_rate).max(1);
+
+        let mut points: Vec<SeekPoint> = vec![];
+        let mut position = 0;
+
+        demuxer.scan_packets(|packet| {
+            let sample = packet.sample_position.unwrap_or(position);
+            position = sample + packet.samples;
+
+            let offset = match packet.position {
+                Some(offset) if sample >= 0 => offset,
+                _ => return,
+            };
+            let sample = sample as u64;
+
+            if points
+                .last()
+                .map_or(true, |last| sample >= last.sample + interval)
+            {
+                points.push(SeekPoint { sample, offset });
+            }
+        })?;
+
+        Ok(SeekIndex {
+            stream_index,
+            sample_rate,
+            points,
+        })
+    }
+
+    /// Whether the index was built from `stream`
+    pub(crate) fn matches(&self, stream: &Stream) -> bool {
+        self.stream_index == stream.index as usize && self.sample_rate == stream.sample_rate()
+    }
+
+    /// The last point at or before `sample`
+    pub fn point_before(&self, sample: u64) -> Option<SeekPoint> {
+        let index = self.points.partition_point(|point| point.sample <= sample);
+
+        index.checked_sub(1).map(|index| self.points[index])
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<SeekIndex, Error> {
+        SeekIndex::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Serialize as a small binary format, all integers little endian
+    pub fn write(&self, mut writer: impl Write) -> Result<(), Error> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        writer.write_all(&(self.stream_index as u32).to_le_bytes())?;
+        writer.write_all(&self.sample_rate.to_le_bytes())?;
+        writer.write_all(&(self.points.len() as u64).to_le_bytes())?;
+
+        for point in &self.points {
+            writer.write_all(&point.sample.to_le_bytes())?;
+            writer.write_all(&point.offset.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Read an index serialized by [`write`](#method.write)
+    pub fn read(mut reader: impl Read) -> Result<SeekIndex, Error> {
+        let mut header = [0; 5];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != MAGIC || header[4] != VERSION {
+            return Err(invalid_data("not a seek index of a supported version").into());
+        }
+
+        let stream_index = read_u32(&mut reader)? as usize;
+        let sample_rate = read_u32(&mut reader)?;
+        let len = read_u64(&mut reader)?;
+
+        let mut points = vec![];
+        for _ in 0..len {
+            points.push(SeekPoint {
+                sample: read_u64(&mut reader)?,
+                offset: read_u64(&mut reader)?,
+            });
+        }
+
+        if points
+            .windows(2)
+            .any(|pair| pair[1].sample < pair[0].sample)
+        {
+            return Err(invalid_data("seek points aren't sorted").into());
+        }
+
+        Ok(SeekIndex {
+            stream_index,
+            sample_rate,
+            points,
+        })
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> SeekIndex {
+        SeekIndex {
+            stream_index: 2,
+            sample_rate: 44100,
+            points: vec![
+                SeekPoint {
+                    sample: 0,
+                    offset: 417,
+                },
+                SeekPoint {
+                    sample: 22_050,
+                    offset: 8_777,
+                },
+                SeekPoint {
+                    sample: 44_928,
+                    offset: 17_553,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let mut bytes = vec![];
+        index().write(&mut bytes).unwrap();
+
+        assert_eq!(&bytes[..5], b"FDSI\x02");
+        assert_eq!(bytes.len(), 5 + 4 + 4 + 8 + 3 * 16);
+        assert_eq!(SeekIndex::read(&bytes[..]).unwrap(), index());
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let path = std::env::temp_dir().join(format!("seek-index-{}.fdsi", std::process::id()));
+
+        index().save(&path).unwrap();
+        let loaded = SeekIndex::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.unwrap(), index());
+    }
+
+    #[test]
+    fn rejects_other_versions_and_unsorted_points() {
+        let mut bytes = vec![];
+        index().write(&mut bytes).unwrap();
+
+        let mut other_version = bytes.clone();
+        other_version[4] = 1;
+        assert!(SeekIndex::read(&other_version[..]).is_err());
+
+        let mut unsorted = index();
+        unsorted.points.swap(0, 2);
+        let mut bytes = vec![];
+        unsorted.write(&mut bytes).unwrap();
+        assert!(SeekIndex::read(&bytes[..]).is_err());
+
+        assert!(SeekIndex::read(&b"FDSI"[..]).is_err());
+    }
+
+    #[test]
+    fn point_before() {
+        let index = index();
+
+        assert_eq!(index.point_before(0).unwrap().offset, 417);
+        assert_eq!(index.point_before(22_049).unwrap().offset, 417);
+        assert_eq!(index.point_before(22_050).unwrap().offset, 8_777);
+        assert_eq!(index.point_before(u64::MAX).unwrap().offset, 17_553);
+        assert_eq!(
+            SeekIndex {
+                points: vec![],
+                ..index
+            }
+            .point_before(10),
+            None
+        );
+    }
+}
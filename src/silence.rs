@@ -0,0 +1,469 @@
+use crate::decoder::Decoder;
+use crate::error::Error;
+use crate::frame::{
+    duration_to_samples, AudioFrame, FrameBuffer, FrameSource, Position, Timestamp,
+};
+
+use ffmpeg_sys_next::AVRational;
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::Duration;
+
+const DEFAULT_THRESHOLD_DB: f64 = -60.0;
+const DEFAULT_MIN_DURATION: Duration = Duration::from_millis(500);
+/// Silence that can only be removed if it's trailing is returned once it's this long, to bound
+/// the memory held back
+const MAX_HELD_SILENCE: Duration = Duration::from_secs(10);
+
+/// What counts as silence for [`detect_silence`](fn.detect_silence.html) and
+/// [`SilenceTrimmer`](struct.SilenceTrimmer.html)
+///
+/// Leading and trailing silence is always detected, silence in between only if enabled with
+/// [`internal`](#method.internal).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SilenceOptions {
+    threshold_db: f64,
+    min_duration: Duration,
+    internal: bool,
+}
+
+impl SilenceOptions {
+    pub fn new() -> SilenceOptions {
+        SilenceOptions {
+            threshold_db: DEFAULT_THRESHOLD_DB,
+            min_duration: DEFAULT_MIN_DURATION,
+            internal: false,
+        }
+    }
+
+    /// Samples of all channels at or below this level in dBFS are silent, defaults to -60 dB
+    pub fn threshold_db(mut self, threshold_db: f64) -> SilenceOptions {
+        self.threshold_db = threshold_db;
+        self
+    }
+
+    /// Shorter silences are ignored, defaults to 500ms
+    pub fn min_duration(mut self, min_duration: Duration) -> SilenceOptions {
+        self.min_duration = min_duration;
+        self
+    }
+
+    /// Also detect silence between audio
+    pub fn internal(mut self, internal: bool) -> SilenceOptions {
+        self.internal = internal;
+        self
+    }
+
+    fn threshold(&self) -> i32 {
+        (10f64.powf(self.threshold_db / 20.0) * 32768.0) as i32
+    }
+}
+
+impl Default for SilenceOptions {
+    fn default() -> SilenceOptions {
+        SilenceOptions::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SilenceKind {
+    Leading,
+    Internal,
+    Trailing,
+}
+
+/// A detected stretch of silence, `end` is exclusive. A file that is silent throughout is
+/// reported as a single `Leading` range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SilenceRange {
+    pub kind: SilenceKind,
+    pub start: Position,
+    pub end: Position,
+}
+
+/// Decode the file and report its silent ranges
+pub fn detect_silence(
+    path: impl AsRef<Path>,
+    options: SilenceOptions,
+) -> Result<Vec<SilenceRange>, Error> {
+    let mut trimmer = SilenceTrimmer::new(Decoder::open(path)?, options);
+    trimmer.detector.hold_silence = false;
+
+    while trimmer.next_frame().is_some() {}
+
+    match trimmer.decoder.take_error() {
+        Some(error) => Err(error),
+        None => Ok(trimmer.detector.ranges),
+    }
+}
+
+/// Removes silence from the output of a [`Decoder`](struct.Decoder.html)
+///
+/// Silence is held back until it's known whether audio follows, so it's only returned once the
+/// audio after it has been decoded. Without internal silence removal, silence after audio is
+/// held back for at most 10 seconds, or `min_duration` if longer, so only trailing silence after
+/// that is removed, though its range starts where the silence does. Timestamps of the returned
+/// frames are those of the decoded stream, so they jump where silence was removed.
+pub struct SilenceTrimmer {
+    decoder: Decoder,
+    detector: SilenceDetector,
+    current_frame: FrameBuffer,
+    current_timestamp: Option<Timestamp>,
+    finished: bool,
+}
+
+impl SilenceTrimmer {
+    pub fn new(decoder: Decoder, options: SilenceOptions) -> SilenceTrimmer {
+        let detector = SilenceDetector::new(
+            options,
+            decoder._channels(),
+            decoder._sample_rate(),
+            decoder._time_base(),
+            decoder.position().samples,
+        );
+
+        SilenceTrimmer {
+            decoder,
+            detector,
+            current_frame: FrameBuffer::default(),
+            current_timestamp: None,
+            finished: false,
+        }
+    }
+
+    /// Silence found so far, removed except for trailing silence returned after being held back
+    pub fn ranges(&self) -> &[SilenceRange] {
+        &self.detector.ranges
+    }
+
+    pub fn into_inner(self) -> Decoder {
+        self.decoder
+    }
+
+    /// Returns the not yet consumed samples of the current frame, or the next frame of audio
+    pub fn next_frame(&mut self) -> Option<AudioFrame> {
+        if !self.current_frame.is_exhausted() {
+            let consumed = (self.current_frame.pos / self.detector.channels_usize()) as u64;
+            let samples = self.current_frame.take_remaining();
+
+            let timestamp = self.current_timestamp.map(|timestamp| {
+                timestamp.offset(consumed, self.decoder._time_base(), self._sample_rate())
+            });
+
+            return Some(self.detector.audio_frame(samples, timestamp));
+        }
+
+        self.next_output()
+    }
+
+    fn next_output(&mut self) -> Option<AudioFrame> {
+        loop {
+            if let Some(frame) = self.detector.output.pop_front() {
+                return Some(frame);
+            }
+
+            if self.finished {
+                return None;
+            }
+
+            match self.decoder.next_frame() {
+                Some(frame) => self.detector.process(frame),
+                None => {
+                    self.detector.finish();
+                    self.finished = true;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn _channels(&self) -> u16 {
+        self.decoder._channels()
+    }
+
+    pub(crate) fn _sample_rate(&self) -> u32 {
+        self.decoder._sample_rate()
+    }
+}
+
+/// Finds silence in decoded frames and queues the frames with it removed
+struct SilenceDetector {
+    options: SilenceOptions,
+    threshold: i32,
+    min_samples: u64,
+    /// Silence held back at most when it can only be trailing
+    max_held_samples: u64,
+    channels: u16,
+    sample_rate: u32,
+    time_base: AVRational,
+    /// Position of the next sample
+    position: u64,
+    /// Whether silence is held back to be returned if it isn't removed, not needed when only
+    /// detecting it
+    hold_silence: bool,
+    audio_seen: bool,
+    silence: Option<SilentRun>,
+    ranges: Vec<SilenceRange>,
+    output: VecDeque<AudioFrame>,
+}
+
+/// Silence that isn't decided on yet
+struct SilentRun {
+    start: u64,
+    /// Samples held back since the start or since they were last returned
+    held: Segment,
+    /// Long enough that it's removed whatever follows, so samples aren't kept
+    dropping: bool,
+}
+
+impl SilenceDetector {
+    fn new(
+        options: SilenceOptions,
+        channels: u16,
+        sample_rate: u32,
+        time_base: AVRational,
+        position: u64,
+    ) -> SilenceDetector {
+        let min_samples = duration_to_samples(options.min_duration, sample_rate);
+        let max_held_samples = duration_to_samples(MAX_HELD_SILENCE, sample_rate).max(min_samples);
+
+        SilenceDetector {
+            options,
+            threshold: options.threshold(),
+            min_samples,
+            max_held_samples,
+            channels,
+            sample_rate,
+            time_base,
+            position,
+            hold_silence: true,
+            audio_seen: false,
+            silence: None,
+            ranges: vec![],
+            output: VecDeque::new(),
+        }
+    }
+
+    fn process(&mut self, frame: AudioFrame) {
+        let channels = self.channels_usize();
+        let time_base = self.time_base;
+        let sample_rate = self.sample_rate;
+
+        let mut segment = Segment::default();
+
+        for (index, samples) in frame.samples.chunks_exact(channels).enumerate() {
+            let timestamp = frame
+                .timestamp
+                .map(|timestamp| timestamp.offset(index as u64, time_base, sample_rate));
+
+            let silent = samples
+                .iter()
+                .all(|sample| i32::from(*sample).abs() <= self.threshold);
+
+            if silent {
+                let position = self.position;
+                let run = self.silence.get_or_insert_with(|| SilentRun {
+                    start: position,
+                    held: Segment::default(),
+                    dropping: false,
+                });
+
+                let length = position + 1 - run.start;
+                let removable = !self.audio_seen || self.options.internal;
+                if !run.dropping {
+                    if length >= self.min_samples && removable {
+                        run.dropping = true;
+                        run.held = Segment::default();
+                    } else if self.hold_silence {
+                        run.held.extend(samples, timestamp);
+                    }
+                }
+
+                // Silence that could only be removed as trailing silence is returned once it's
+                // held back too long, and what follows is held back anew from the same run
+                let held = (run.held.samples.len() / channels) as u64;
+                if !removable && held >= self.max_held_samples {
+                    let held = std::mem::take(&mut run.held);
+                    segment.extend(&held.samples, held.timestamp);
+                }
+            } else {
+                if let Some(run) = self.silence.take() {
+                    let kind = if self.audio_seen {
+                        SilenceKind::Internal
+                    } else {
+                        SilenceKind::Leading
+                    };
+
+                    if run.dropping {
+                        self.record(kind, run.start);
+                        self.push_segment(std::mem::take(&mut segment));
+                    } else {
+                        segment.extend(&run.held.samples, run.held.timestamp);
+                    }
+                }
+
+                self.audio_seen = true;
+                segment.extend(samples, timestamp);
+            }
+
+            self.position += 1;
+        }
+
+        self.push_segment(segment);
+    }
+
+    /// Decide on silence at the end of the stream
+    fn finish(&mut self) {
+        if let Some(run) = self.silence.take() {
+            let kind = if self.audio_seen {
+                SilenceKind::Trailing
+            } else {
+                SilenceKind::Leading
+            };
+
+            if run.dropping || self.position - run.start >= self.min_samples {
+                self.record(kind, run.start);
+            } else {
+                self.push_segment(run.held);
+            }
+        }
+    }
+
+    /// Record silence from `start` up to the current position
+    fn record(&mut self, kind: SilenceKind, start: u64) {
+        let sample_rate = self.sample_rate;
+
+        self.ranges.push(SilenceRange {
+            kind,
+            start: Position::new(start, sample_rate),
+            end: Position::new(self.position, sample_rate),
+        });
+    }
+
+    fn push_segment(&mut self, segment: Segment) {
+        if !segment.samples.is_empty() {
+            let frame = self.audio_frame(segment.samples, segment.timestamp);
+            self.output.push_back(frame);
+        }
+    }
+
+    fn audio_frame(&self, samples: Vec<i16>, timestamp: Option<Timestamp>) -> AudioFrame {
+        AudioFrame {
+            samples,
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            timestamp,
+        }
+    }
+
+    fn channels_usize(&self) -> usize {
+        self.channels.max(1) as usize
+    }
+}
+
+impl Iterator for SilenceTrimmer {
+    type Item = i16;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_sample()
+    }
+}
+
+impl FrameSource for SilenceTrimmer {
+    fn frame_buffer(&self) -> &FrameBuffer {
+        &self.current_frame
+    }
+
+    fn frame_buffer_mut(&mut self) -> &mut FrameBuffer {
+        &mut self.current_frame
+    }
+
+    fn load_frame(&mut self) -> bool {
+        match self.next_output() {
+            Some(frame) => {
+                self.current_frame.set(frame.samples);
+                self.current_timestamp = frame.timestamp;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Contiguous samples to return, with the timestamp of the first
+#[derive(Default)]
+struct Segment {
+    samples: Vec<i16>,
+    timestamp: Option<Timestamp>,
+}
+
+impl Segment {
+    fn extend(&mut self, samples: &[i16], timestamp: Option<Timestamp>) {
+        if self.samples.is_empty() {
+            self.timestamp = timestamp;
+        }
+
+        self.samples.extend_from_slice(samples);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 100;
+
+    fn detector() -> SilenceDetector {
+        let time_base = AVRational {
+            num: 1,
+            den: SAMPLE_RATE as i32,
+        };
+
+        SilenceDetector::new(SilenceOptions::new(), 1, SAMPLE_RATE, time_base, 0)
+    }
+
+    /// One second of audio followed by 15 seconds of silence, a second per frame
+    fn process_trailing_silence(detector: &mut SilenceDetector) {
+        let second = SAMPLE_RATE as usize;
+
+        detector.process(detector.audio_frame(vec![10_000; second], None));
+        for _ in 0..15 {
+            detector.process(detector.audio_frame(vec![0; second], None));
+        }
+        detector.finish();
+    }
+
+    fn output_len(detector: &SilenceDetector) -> usize {
+        detector
+            .output
+            .iter()
+            .map(|frame| frame.samples.len())
+            .sum()
+    }
+
+    #[test]
+    fn long_trailing_silence_starts_after_the_audio() {
+        let mut detector = detector();
+        process_trailing_silence(&mut detector);
+
+        let range = detector.ranges[0];
+        assert_eq!(detector.ranges.len(), 1);
+        assert_eq!(range.kind, SilenceKind::Trailing);
+        assert_eq!(range.start.samples, 100);
+        assert_eq!(range.end.samples, 1600);
+
+        // The audio and the 10 seconds of silence held back at most
+        assert_eq!(output_len(&detector), 1100);
+    }
+
+    #[test]
+    fn detection_holds_no_silence() {
+        let mut detector = detector();
+        detector.hold_silence = false;
+        process_trailing_silence(&mut detector);
+
+        assert_eq!(detector.ranges[0].start.samples, 100);
+        assert_eq!(output_len(&detector), 100);
+    }
+}
@@ -0,0 +1,432 @@
+use crate::decoder::Decoder;
+use crate::error::Error;
+use crate::frame::{samples_to_duration, AudioFrame};
+
+use log::warn;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use std::f32::consts::PI;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_WINDOW_SIZE: usize = 2048;
+const DEFAULT_HOP_SIZE: usize = 512;
+
+/// Lowest level in dB shown in exported images, quieter bins are black
+#[cfg(feature = "analysis_png")]
+const PNG_FLOOR_DB: f32 = -120.0;
+
+/// Taper applied to each block of samples before the FFT
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WindowFunction {
+    Rectangular,
+    #[default]
+    Hann,
+    Hamming,
+    Blackman,
+}
+
+impl WindowFunction {
+    /// The `size` coefficients of the periodic window
+    pub fn coefficients(self, size: usize) -> Vec<f32> {
+        let phase = |n: usize| 2.0 * PI * n as f32 / size as f32;
+
+        (0..size)
+            .map(|n| match self {
+                WindowFunction::Rectangular => 1.0,
+                WindowFunction::Hann => 0.5 - 0.5 * phase(n).cos(),
+                WindowFunction::Hamming => 0.54 - 0.46 * phase(n).cos(),
+                WindowFunction::Blackman => {
+                    0.42 - 0.5 * phase(n).cos() + 0.08 * (2.0 * phase(n)).cos()
+                }
+            })
+            .collect()
+    }
+}
+
+/// How a [`SpectrumAnalyzer`](struct.SpectrumAnalyzer.html) splits the signal into blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpectrogramOptions {
+    window_size: usize,
+    hop_size: usize,
+    window_function: WindowFunction,
+}
+
+impl SpectrogramOptions {
+    pub fn new() -> SpectrogramOptions {
+        SpectrogramOptions {
+            window_size: DEFAULT_WINDOW_SIZE,
+            hop_size: DEFAULT_HOP_SIZE,
+            window_function: WindowFunction::default(),
+        }
+    }
+
+    /// Samples per FFT, defaults to 2048. Powers of two are fastest.
+    pub fn window_size(mut self, window_size: usize) -> SpectrogramOptions {
+        self.window_size = window_size.max(2);
+        self
+    }
+
+    /// Samples between the start of consecutive windows, defaults to 512
+    pub fn hop_size(mut self, hop_size: usize) -> SpectrogramOptions {
+        self.hop_size = hop_size.max(1);
+        self
+    }
+
+    /// Defaults to `Hann`
+    pub fn window_function(mut self, window_function: WindowFunction) -> SpectrogramOptions {
+        self.window_function = window_function;
+        self
+    }
+}
+
+impl Default for SpectrogramOptions {
+    fn default() -> SpectrogramOptions {
+        SpectrogramOptions::new()
+    }
+}
+
+/// Short-time Fourier transform of a stream
+///
+/// Magnitudes are scaled so a full scale sine shows as `1.0` in its bin.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spectrogram {
+    pub sample_rate: u32,
+    pub window_size: usize,
+    pub hop_size: usize,
+    /// Magnitudes as `[channel][frame][bin]`, with bins from 0 Hz up to half the sample rate
+    pub channels: Vec<Vec<Vec<f32>>>,
+}
+
+impl Spectrogram {
+    pub fn num_frames(&self) -> usize {
+        self.channels.first().map_or(0, Vec::len)
+    }
+
+    pub fn num_bins(&self) -> usize {
+        self.window_size / 2 + 1
+    }
+
+    /// Center frequency of `bin` in Hz
+    pub fn bin_frequency(&self, bin: usize) -> f32 {
+        bin as f32 * self.sample_rate as f32 / self.window_size as f32
+    }
+
+    /// Start of the window of `frame`
+    pub fn frame_time(&self, frame: usize) -> Duration {
+        samples_to_duration((frame * self.hop_size) as u64, self.sample_rate)
+    }
+
+    /// Magnitudes of `channel` as a row-major `num_frames` x `num_bins` matrix
+    pub fn matrix(&self, channel: usize) -> Vec<f32> {
+        self.channels[channel].concat()
+    }
+
+    /// Copy with magnitudes converted to dB, silence clamped to -200 dB
+    pub fn to_decibels(&self) -> Spectrogram {
+        Spectrogram {
+            channels: self
+                .channels
+                .iter()
+                .map(|frames| {
+                    frames
+                        .iter()
+                        .map(|bins| bins.iter().map(|magnitude| decibels(*magnitude)).collect())
+                        .collect()
+                })
+                .collect(),
+            ..*self
+        }
+    }
+
+    /// Save `channel` as an 8bit grayscale PNG, time running left to right and frequency
+    /// bottom to top. Brightness is the level in dB, from -120 dB up to 0 dB.
+    #[cfg(feature = "analysis_png")]
+    pub fn write_png(&self, path: impl AsRef<Path>, channel: usize) -> Result<(), Error> {
+        let frames = &self.channels[channel];
+        let width = frames.len();
+        let height = self.num_bins();
+
+        let mut pixels = vec![0; width * height];
+        for (x, bins) in frames.iter().enumerate() {
+            for (bin, magnitude) in bins.iter().enumerate() {
+                let level = (decibels(*magnitude) - PNG_FLOOR_DB) / -PNG_FLOOR_DB;
+                let y = height - 1 - bin;
+
+                pixels[y * width + x] = (level.clamp(0.0, 1.0) * 255.0).round() as u8;
+            }
+        }
+
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+
+        let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&pixels)?;
+        writer.finish()?;
+
+        Ok(())
+    }
+}
+
+/// Computes a [`Spectrogram`](struct.Spectrogram.html) from decoded samples as they come in
+pub struct SpectrumAnalyzer {
+    options: SpectrogramOptions,
+    sample_rate: u32,
+    window: Vec<f32>,
+    /// Scales magnitudes so a full scale sine is `1.0`
+    scale: f32,
+    fft: Arc<dyn Fft<f32>>,
+    buffer: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    /// Samples per channel not yet moved past by the hop
+    pending: Vec<Vec<f32>>,
+    /// Number of leading `pending` samples that were part of a window already
+    analyzed: usize,
+    /// Samples still to drop before the next window, if the hop is larger than the window
+    skip: usize,
+    frames: Vec<Vec<Vec<f32>>>,
+}
+
+impl SpectrumAnalyzer {
+    pub fn new(channels: u16, sample_rate: u32, options: SpectrogramOptions) -> SpectrumAnalyzer {
+        let channels = channels.max(1) as usize;
+        let window = options.window_function.coefficients(options.window_size);
+        let scale = 2.0 / window.iter().sum::<f32>().max(f32::EPSILON);
+
+        let fft = FftPlanner::new().plan_fft_forward(options.window_size);
+        let scratch = vec![Complex::default(); fft.get_inplace_scratch_len()];
+
+        SpectrumAnalyzer {
+            options,
+            sample_rate,
+            window,
+            scale,
+            fft,
+            buffer: vec![Complex::default(); options.window_size],
+            scratch,
+            pending: vec![vec![]; channels],
+            analyzed: 0,
+            skip: 0,
+            frames: vec![vec![]; channels],
+        }
+    }
+
+    /// For the samples returned by `decoder`
+    pub fn for_decoder(decoder: &Decoder, options: SpectrogramOptions) -> SpectrumAnalyzer {
+        SpectrumAnalyzer::new(decoder._channels(), decoder._sample_rate(), options)
+    }
+
+    pub fn add_frame(&mut self, frame: &AudioFrame) {
+        self.add_planar(&frame.planar_f32());
+    }
+
+    /// Add samples split by channel, as returned by
+    /// [`AudioFrame::planar_f32`](struct.AudioFrame.html#method.planar_f32). All channels must
+    /// have the same number of samples, otherwise the samples are ignored.
+    pub fn add_planar(&mut self, channels: &[Vec<f32>]) {
+        let len = channels.first().map_or(0, Vec::len);
+        if channels.len() < self.pending.len()
+            || channels.iter().any(|samples| samples.len() != len)
+        {
+            warn!(
+                "Ignoring samples of {} channels of unequal length, expected {} channels",
+                channels.len(),
+                self.pending.len()
+            );
+            return;
+        }
+
+        let skip = self.skip.min(len);
+        self.skip -= skip;
+
+        for (pending, samples) in self.pending.iter_mut().zip(channels) {
+            pending.extend_from_slice(&samples[skip..]);
+        }
+
+        let window_size = self.options.window_size;
+        let hop_size = self.options.hop_size;
+
+        while self.pending[0].len() >= window_size {
+            for channel in 0..self.pending.len() {
+                let magnitudes = self.analyze(channel);
+                self.frames[channel].push(magnitudes);
+            }
+
+            // A hop larger than the window also skips samples that haven't arrived yet
+            let drain = hop_size.min(self.pending[0].len());
+            for pending in &mut self.pending {
+                pending.drain(..drain);
+            }
+
+            self.skip = hop_size - drain;
+            self.analyzed = window_size.saturating_sub(drain);
+        }
+    }
+
+    /// Analyze the samples left over, padded with silence, and return the result
+    pub fn finish(mut self) -> Spectrogram {
+        let unanalyzed = self.pending[0].len() > self.analyzed;
+
+        if unanalyzed || self.frames[0].is_empty() {
+            let window_size = self.options.window_size;
+
+            for pending in &mut self.pending {
+                pending.resize(window_size, 0.0);
+            }
+            for channel in 0..self.pending.len() {
+                let magnitudes = self.analyze(channel);
+                self.frames[channel].push(magnitudes);
+            }
+        }
+
+        Spectrogram {
+            sample_rate: self.sample_rate,
+            window_size: self.options.window_size,
+            hop_size: self.options.hop_size,
+            channels: self.frames,
+        }
+    }
+
+    /// Magnitude spectrum of the first window of pending samples of `channel`
+    fn analyze(&mut self, channel: usize) -> Vec<f32> {
+        let samples = &self.pending[channel];
+
+        for ((value, sample), coefficient) in self.buffer.iter_mut().zip(samples).zip(&self.window)
+        {
+            *value = Complex::new(sample * coefficient, 0.0);
+        }
+
+        self.fft
+            .process_with_scratch(&mut self.buffer, &mut self.scratch);
+
+        magnitudes(&self.buffer, self.scale)
+    }
+}
+
+/// Magnitude spectrum of `samples`, scaled so a full scale sine is `1.0`. Has
+/// `samples.len() / 2 + 1` bins, none if there are no samples.
+pub fn spectrum(samples: &[f32], window_function: WindowFunction) -> Vec<f32> {
+    if samples.is_empty() {
+        return vec![];
+    }
+
+    let window = window_function.coefficients(samples.len());
+    let scale = 2.0 / window.iter().sum::<f32>().max(f32::EPSILON);
+
+    let mut buffer: Vec<_> = samples
+        .iter()
+        .zip(&window)
+        .map(|(sample, coefficient)| Complex::new(sample * coefficient, 0.0))
+        .collect();
+
+    FftPlanner::new()
+        .plan_fft_forward(samples.len())
+        .process(&mut buffer);
+
+    magnitudes(&buffer, scale)
+}
+
+/// Decode the whole stream and compute its spectrogram
+pub fn spectrogram(
+    path: impl AsRef<Path>,
+    options: SpectrogramOptions,
+) -> Result<Spectrogram, Error> {
+    let mut decoder = Decoder::open(path)?;
+    let mut analyzer = SpectrumAnalyzer::for_decoder(&decoder, options);
+
+    while let Some(frame) = decoder.next_frame() {
+        analyzer.add_frame(&frame);
+    }
+
+    match decoder.take_error() {
+        Some(error) => Err(error),
+        None => Ok(analyzer.finish()),
+    }
+}
+
+/// The non-negative frequency bins of an FFT. DC and Nyquist only appear once in the full
+/// spectrum, so aren't doubled.
+fn magnitudes(spectrum: &[Complex<f32>], scale: f32) -> Vec<f32> {
+    let size = spectrum.len();
+
+    spectrum[..size / 2 + 1]
+        .iter()
+        .enumerate()
+        .map(|(bin, value)| {
+            let magnitude = value.norm() * scale;
+
+            if bin == 0 || bin * 2 == size {
+                magnitude / 2.0
+            } else {
+                magnitude
+            }
+        })
+        .collect()
+}
+
+fn decibels(magnitude: f32) -> f32 {
+    20.0 * magnitude.max(1e-10).log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn analyzer(window_size: usize, hop_size: usize) -> SpectrumAnalyzer {
+        let options = SpectrogramOptions::new()
+            .window_size(window_size)
+            .hop_size(hop_size);
+
+        SpectrumAnalyzer::new(1, 8000, options)
+    }
+
+    #[test]
+    fn hop_larger_than_window() {
+        let samples: Vec<f32> = (0..10).map(|n| n as f32).collect();
+
+        let mut at_once = analyzer(2, 3);
+        at_once.add_planar(std::slice::from_ref(&samples));
+
+        let mut one_by_one = analyzer(2, 3);
+        for sample in &samples {
+            one_by_one.add_planar(&[vec![*sample]]);
+        }
+
+        // Windows start at 0, 3 and 6
+        assert_eq!(at_once.frames[0].len(), 3);
+        assert_eq!(at_once.frames, one_by_one.frames);
+    }
+
+    #[test]
+    fn mismatched_channels_are_ignored() {
+        let mut analyzer = SpectrumAnalyzer::new(2, 8000, SpectrogramOptions::new());
+
+        analyzer.add_planar(&[vec![0.0; 4096]]);
+        analyzer.add_planar(&[vec![0.0; 4096], vec![0.0; 100]]);
+
+        assert!(analyzer.pending.iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn full_scale_sine() {
+        let size = 1024;
+        let samples: Vec<f32> = (0..size)
+            .map(|n| (2.0 * PI * 64.0 * n as f32 / size as f32).sin())
+            .collect();
+
+        let magnitudes = spectrum(&samples, WindowFunction::Hann);
+
+        assert_eq!(magnitudes.len(), size / 2 + 1);
+        assert!((magnitudes[64] - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn empty_spectrum() {
+        assert!(spectrum(&[], WindowFunction::Hann).is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+use crate::format::Stream;
+
+use ffmpeg_sys_next::{
+    AV_DISPOSITION_COMMENT, AV_DISPOSITION_DEFAULT, AV_DISPOSITION_DUB,
+    AV_DISPOSITION_HEARING_IMPAIRED, AV_DISPOSITION_ORIGINAL, AV_DISPOSITION_VISUAL_IMPAIRED,
+};
+
+/// ISO 639-2 languages with a bibliographic code differing from the terminology code, both
+/// of which are found in files
+const BIBLIOGRAPHIC_CODES: [(&str, &str); 20] = [
+    ("alb", "sqi"),
+    ("arm", "hye"),
+    ("baq", "eus"),
+    ("bur", "mya"),
+    ("chi", "zho"),
+    ("cze", "ces"),
+    ("dut", "nld"),
+    ("fre", "fra"),
+    ("geo", "kat"),
+    ("ger", "deu"),
+    ("gre", "ell"),
+    ("ice", "isl"),
+    ("mac", "mkd"),
+    ("mao", "mri"),
+    ("may", "msa"),
+    ("per", "fas"),
+    ("rum", "ron"),
+    ("slo", "slk"),
+    ("tib", "bod"),
+    ("wel", "cym"),
+];
+
+/// Role of a stream, as flagged by the container
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    /// Played unless the user picks another stream
+    Default,
+    Dub,
+    Original,
+    Commentary,
+    HearingImpaired,
+    /// Audio description of the picture
+    VisualImpaired,
+}
+
+impl Disposition {
+    fn flag(self) -> i32 {
+        match self {
+            Disposition::Default => AV_DISPOSITION_DEFAULT,
+            Disposition::Dub => AV_DISPOSITION_DUB,
+            Disposition::Original => AV_DISPOSITION_ORIGINAL,
+            Disposition::Commentary => AV_DISPOSITION_COMMENT,
+            Disposition::HearingImpaired => AV_DISPOSITION_HEARING_IMPAIRED,
+            Disposition::VisualImpaired => AV_DISPOSITION_VISUAL_IMPAIRED,
+        }
+    }
+}
+
+/// Which audio stream of a file with several to decode, e.g. the tracks of a movie
+///
+/// Without criteria the first audio stream is picked. If several streams match the criteria,
+/// main streams are preferred over commentary and accessibility ones unless asked for, then
+/// the stream flagged as default, then the first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamSelector {
+    language: Option<String>,
+    disposition: Option<Disposition>,
+}
+
+impl StreamSelector {
+    pub fn new() -> StreamSelector {
+        StreamSelector::default()
+    }
+
+    /// Only streams tagged with this three-letter ISO 639-2 code, e.g. `eng` or `ger`, as
+    /// containers store them. Bibliographic and terminology codes are interchangeable, so `deu`
+    /// also matches `ger`, but two-letter ISO 639-1 codes such as `de` match nothing.
+    pub fn language(mut self, language: impl Into<String>) -> StreamSelector {
+        self.language = Some(normalize_language(&language.into()));
+        self
+    }
+
+    /// Only streams flagged with `disposition`
+    pub fn disposition(mut self, disposition: Disposition) -> StreamSelector {
+        self.disposition = Some(disposition);
+        self
+    }
+
+    /// The best matching of `streams`, which must all be audio streams
+    pub(crate) fn select(&self, streams: Vec<Stream>) -> Option<Stream> {
+        if self.language.is_none() && self.disposition.is_none() {
+            return streams.into_iter().next();
+        }
+
+        let secondary = AV_DISPOSITION_COMMENT
+            | AV_DISPOSITION_HEARING_IMPAIRED
+            | AV_DISPOSITION_VISUAL_IMPAIRED;
+
+        streams
+            .into_iter()
+            .filter(|stream| self.matches(stream))
+            .min_by_key(|stream| {
+                let disposition = stream.disposition();
+                let is_secondary = self.disposition.is_none() && disposition & secondary != 0;
+                let is_default = disposition & AV_DISPOSITION_DEFAULT != 0;
+
+                (is_secondary, !is_default, stream.index)
+            })
+    }
+
+    fn matches(&self, stream: &Stream) -> bool {
+        let language_matches = self.language.as_ref().map_or(true, |language| {
+            stream
+                .metadata()
+                .get("language")
+                .is_some_and(|tag| normalize_language(tag) == *language)
+        });
+
+        let disposition_matches = self.disposition.map_or(true, |disposition| {
+            stream.disposition() & disposition.flag() != 0
+        });
+
+        language_matches && disposition_matches
+    }
+
+    /// Summary of the criteria for error messages
+    pub(crate) fn describe(&self) -> String {
+        let mut criteria = vec![];
+        if let Some(language) = &self.language {
+            criteria.push(format!("language {}", language));
+        }
+        if let Some(disposition) = self.disposition {
+            criteria.push(format!("disposition {:?}", disposition));
+        }
+
+        criteria.join(" and ")
+    }
+}
+
+/// Lowercase terminology code for `language`
+fn normalize_language(language: &str) -> String {
+    let language = language.trim().to_ascii_lowercase();
+
+    BIBLIOGRAPHIC_CODES
+        .iter()
+        .find(|(bibliographic, _)| *bibliographic == language)
+        .map_or(language, |(_, terminology)| (*terminology).to_owned())
+}
@@ -0,0 +1,357 @@
+use crate::decoder::Decoder;
+use crate::error::Error;
+use crate::frame::{AudioFrame, Position};
+use crate::spectrogram::WindowFunction;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use std::path::Path;
+use std::sync::Arc;
+
+const FRAME_SIZE: usize = 2048;
+const HOP_SIZE: usize = 512;
+
+const MIN_BPM: f64 = 60.0;
+const MAX_BPM: f64 = 200.0;
+/// Tempos far from this are considered less likely, to avoid picking half or double the tempo
+const PREFERRED_BPM: f64 = 120.0;
+/// Width in octaves of the preference for tempos around `PREFERRED_BPM`
+const TEMPO_SPREAD: f64 = 1.0;
+
+/// Scale of the log compression of magnitudes before the spectral flux
+const COMPRESSION: f32 = 1000.0;
+/// Frames on either side an onset must be the maximum of
+const PEAK_RADIUS: usize = 3;
+/// Frames before and after an onset its strength is compared to
+const MEAN_WINDOW: (usize, usize) = (16, 4);
+/// How far above the local mean an onset must be, relative to the strongest onset
+const ONSET_DELTA: f32 = 0.07;
+/// How strictly beats follow the tempo over following onsets
+const TIGHTNESS: f64 = 100.0;
+
+/// Result of a [`TempoDetector`](struct.TempoDetector.html)
+#[derive(Debug, Clone, PartialEq)]
+pub struct TempoAnalysis {
+    /// Estimated tempo in beats per minute, `None` if there weren't enough onsets
+    pub bpm: Option<f64>,
+    /// How clearly the onsets repeat at the estimated tempo, from `0.0` to `1.0`
+    pub confidence: f64,
+    /// Beat grid following the tempo, snapped to onsets where there are any
+    pub beats: Vec<Beat>,
+    pub onsets: Vec<Onset>,
+}
+
+/// Start of a note or percussive hit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Onset {
+    pub position: Position,
+    /// Relative to the strongest onset, from `0.0` to `1.0`
+    pub strength: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Beat {
+    pub position: Position,
+    /// Onset strength at the beat relative to the strongest onset, from `0.0` to `1.0`. Low for
+    /// beats filled in where the music has no onset.
+    pub confidence: f32,
+}
+
+/// Estimates tempo, beats and onsets from decoded samples as they come in
+///
+/// Onsets are found from the spectral flux of the mono mix. The tempo is the strongest
+/// periodicity of the onset strength between 60 and 200 BPM, and beats are placed by dynamic
+/// programming to follow both the tempo and the onsets.
+pub struct TempoDetector {
+    channels: usize,
+    sample_rate: u32,
+    window: Vec<f32>,
+    fft: Arc<dyn Fft<f32>>,
+    buffer: Vec<Complex<f32>>,
+    /// Mono samples not yet moved past by the hop
+    pending: Vec<f32>,
+    previous: Vec<f32>,
+    /// Onset strength per hop
+    envelope: Vec<f32>,
+}
+
+impl TempoDetector {
+    pub fn new(channels: u16, sample_rate: u32) -> TempoDetector {
+        TempoDetector {
+            channels: channels.max(1) as usize,
+            sample_rate: sample_rate.max(1),
+            window: WindowFunction::Hann.coefficients(FRAME_SIZE),
+            fft: FftPlanner::new().plan_fft_forward(FRAME_SIZE),
+            buffer: vec![Complex::default(); FRAME_SIZE],
+            pending: vec![],
+            previous: vec![],
+            envelope: vec![],
+        }
+    }
+
+    /// For the samples returned by `decoder`
+    pub fn for_decoder(decoder: &Decoder) -> TempoDetector {
+        TempoDetector::new(decoder._channels(), decoder._sample_rate())
+    }
+
+    pub fn add_frame(&mut self, frame: &AudioFrame) {
+        self.add_samples(&frame.samples);
+    }
+
+    /// Add interleaved samples. The length must be a multiple of the number of channels.
+    pub fn add_samples(&mut self, samples: &[i16]) {
+        let scale = 1.0 / (32768.0 * self.channels as f32);
+
+        self.pending.extend(
+            samples
+                .chunks_exact(self.channels)
+                .map(|frame| frame.iter().map(|sample| f32::from(*sample)).sum::<f32>() * scale),
+        );
+
+        while self.pending.len() >= FRAME_SIZE {
+            let strength = self.onset_strength();
+            self.envelope.push(strength);
+
+            self.pending.drain(..HOP_SIZE);
+        }
+    }
+
+    pub fn analysis(&self) -> TempoAnalysis {
+        let max = self.envelope.iter().copied().fold(0.0, f32::max);
+        if max <= 0.0 {
+            return TempoAnalysis {
+                bpm: None,
+                confidence: 0.0,
+                beats: vec![],
+                onsets: vec![],
+            };
+        }
+
+        let normalized: Vec<f32> = self.envelope.iter().map(|value| value / max).collect();
+
+        let onsets = self.onsets(&normalized);
+        let (period, confidence) = match self.period(&normalized) {
+            Some(period) => period,
+            None => {
+                return TempoAnalysis {
+                    bpm: None,
+                    confidence: 0.0,
+                    beats: vec![],
+                    onsets,
+                }
+            }
+        };
+
+        let beats = self
+            .beats(&normalized, period)
+            .into_iter()
+            .map(|frame| Beat {
+                position: self.frame_position(frame),
+                confidence: normalized[frame],
+            })
+            .collect();
+
+        TempoAnalysis {
+            bpm: Some(60.0 * self.envelope_rate() / period),
+            confidence,
+            beats,
+            onsets,
+        }
+    }
+
+    /// Positive change in log magnitude from the previous frame, summed over all bins
+    fn onset_strength(&mut self) -> f32 {
+        for ((value, sample), coefficient) in
+            self.buffer.iter_mut().zip(&self.pending).zip(&self.window)
+        {
+            *value = Complex::new(sample * coefficient, 0.0);
+        }
+
+        self.fft.process(&mut self.buffer);
+
+        let magnitudes: Vec<f32> = self.buffer[..FRAME_SIZE / 2 + 1]
+            .iter()
+            .map(|value| (1.0 + COMPRESSION * value.norm()).ln())
+            .collect();
+
+        let flux = self
+            .previous
+            .iter()
+            .zip(&magnitudes)
+            .map(|(previous, current)| (current - previous).max(0.0))
+            .sum();
+
+        self.previous = magnitudes;
+
+        flux
+    }
+
+    /// Local maxima of the envelope that stand out from their surroundings
+    fn onsets(&self, envelope: &[f32]) -> Vec<Onset> {
+        let mut onsets = vec![];
+        let mut last_onset = None;
+
+        for (frame, value) in envelope.iter().enumerate() {
+            let around = |before: usize, after: usize| {
+                &envelope[frame.saturating_sub(before)..(frame + after + 1).min(envelope.len())]
+            };
+
+            let is_peak = around(PEAK_RADIUS, PEAK_RADIUS)
+                .iter()
+                .all(|other| other <= value);
+
+            let neighbours = around(MEAN_WINDOW.0, MEAN_WINDOW.1);
+            let mean = neighbours.iter().sum::<f32>() / neighbours.len() as f32;
+
+            // Plateaus are reported once
+            let too_close = last_onset.is_some_and(|last| frame - last <= PEAK_RADIUS);
+
+            if is_peak && *value >= mean + ONSET_DELTA && !too_close {
+                onsets.push(Onset {
+                    position: self.frame_position(frame),
+                    strength: *value,
+                });
+                last_onset = Some(frame);
+            }
+        }
+
+        onsets
+    }
+
+    /// Beat period in envelope frames, with its confidence, from the autocorrelation of the
+    /// envelope weighted towards `PREFERRED_BPM`
+    fn period(&self, envelope: &[f32]) -> Option<(f64, f64)> {
+        let rate = self.envelope_rate();
+        let min_lag = (60.0 * rate / MAX_BPM).floor().max(1.0) as usize;
+        let max_lag = (60.0 * rate / MIN_BPM).ceil() as usize;
+
+        if envelope.len() <= max_lag + 1 {
+            return None;
+        }
+
+        let mean = envelope.iter().sum::<f32>() / envelope.len() as f32;
+        let centered: Vec<f64> = envelope
+            .iter()
+            .map(|value| f64::from(value - mean))
+            .collect();
+
+        let autocorrelation = |lag: usize| -> f64 {
+            centered
+                .iter()
+                .zip(&centered[lag..])
+                .map(|(a, b)| a * b)
+                .sum::<f64>()
+                / (centered.len() - lag) as f64
+        };
+
+        let energy = autocorrelation(0);
+        if energy <= 0.0 {
+            return None;
+        }
+
+        let correlations: Vec<f64> = (min_lag - 1..=max_lag + 1).map(autocorrelation).collect();
+        let weight = |lag: usize| {
+            let bpm = 60.0 * rate / lag as f64;
+            (-0.5 * ((bpm / PREFERRED_BPM).log2() / TEMPO_SPREAD).powi(2)).exp()
+        };
+
+        let (best, _) = (1..correlations.len() - 1)
+            .map(|index| (index, correlations[index] * weight(min_lag - 1 + index)))
+            .fold((1, f64::MIN), |best, candidate| {
+                if candidate.1 > best.1 {
+                    candidate
+                } else {
+                    best
+                }
+            });
+
+        // Parabolic interpolation between neighbouring lags
+        let (before, peak, after) = (
+            correlations[best - 1],
+            correlations[best],
+            correlations[best + 1],
+        );
+        let curvature = before - 2.0 * peak + after;
+        let offset = if curvature < 0.0 {
+            (0.5 * (before - after) / curvature).clamp(-0.5, 0.5)
+        } else {
+            0.0
+        };
+
+        let period = (min_lag - 1 + best) as f64 + offset;
+        let confidence = (peak / energy).clamp(0.0, 1.0);
+
+        Some((period, confidence))
+    }
+
+    /// Frames of beats `period` apart that line up best with the onsets
+    fn beats(&self, envelope: &[f32], period: f64) -> Vec<usize> {
+        let mut score = vec![0.0; envelope.len()];
+        let mut previous_beat = vec![None; envelope.len()];
+
+        let min_gap = (period / 2.0).round().max(1.0) as usize;
+        let max_gap = (period * 2.0).round() as usize;
+
+        for frame in 0..envelope.len() {
+            let best = (min_gap..=max_gap.min(frame))
+                .map(|gap| {
+                    let penalty = TIGHTNESS * (gap as f64 / period).ln().powi(2);
+                    (frame - gap, score[frame - gap] - penalty)
+                })
+                .fold(None, |best: Option<(usize, f64)>, candidate| match best {
+                    Some(best) if best.1 >= candidate.1 => Some(best),
+                    _ => Some(candidate),
+                });
+
+            score[frame] = f64::from(envelope[frame]);
+            if let Some((beat, beat_score)) = best.filter(|(_, beat_score)| *beat_score > 0.0) {
+                score[frame] += beat_score;
+                previous_beat[frame] = Some(beat);
+            }
+        }
+
+        // Last beat is the best scoring frame within a period of the end
+        let start = envelope.len().saturating_sub(period.ceil() as usize);
+        let last = (start..envelope.len()).fold(start, |best, frame| {
+            if score[frame] > score[best] {
+                frame
+            } else {
+                best
+            }
+        });
+
+        let mut beats = vec![last];
+        while let Some(beat) = previous_beat[*beats.last().unwrap()] {
+            beats.push(beat);
+        }
+        beats.reverse();
+
+        beats
+    }
+
+    /// Onset strength frames per second
+    fn envelope_rate(&self) -> f64 {
+        f64::from(self.sample_rate) / HOP_SIZE as f64
+    }
+
+    /// Center of the analysis frame `frame`
+    fn frame_position(&self, frame: usize) -> Position {
+        Position::new((frame * HOP_SIZE + FRAME_SIZE / 2) as u64, self.sample_rate)
+    }
+}
+
+/// Decode the whole stream and estimate its tempo, beats and onsets
+pub fn detect_tempo(path: impl AsRef<Path>) -> Result<TempoAnalysis, Error> {
+    let mut decoder = Decoder::open(path)?;
+    let mut detector = TempoDetector::for_decoder(&decoder);
+
+    while let Some(frame) = decoder.next_frame() {
+        detector.add_frame(&frame);
+    }
+
+    match decoder.take_error() {
+        Some(error) => Err(error),
+        None => Ok(detector.analysis()),
+    }
+}
@@ -0,0 +1,245 @@
+use crate::decoder::Decoder;
+use crate::encoder::{AudioCodec, Encoder};
+use crate::error::Error;
+use crate::metadata::Metadata;
+
+use log::warn;
+
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+/// Tags the demuxer or muxer of a container sets itself, which don't describe the recording
+const CONTAINER_TAGS: [&str; 5] = [
+    "encoder",
+    "major_brand",
+    "minor_version",
+    "compatible_brands",
+    "handler_name",
+];
+
+/// Options for [`transcode`](fn.transcode.html)
+///
+/// Unset options fall back to the defaults of the [`Encoder`](struct.Encoder.html), i.e. the
+/// container is guessed from the output extension and the input rate and channels are kept if
+/// the codec supports them.
+pub struct TranscodeOptions {
+    codec: Option<AudioCodec>,
+    container: Option<String>,
+    bit_rate: Option<u64>,
+    channels: Option<u16>,
+    sample_rate: Option<u32>,
+    copy_metadata: bool,
+    copy_cover_art: bool,
+    progress: Option<Box<dyn FnMut(Progress)>>,
+}
+
+impl TranscodeOptions {
+    pub fn new() -> TranscodeOptions {
+        TranscodeOptions {
+            codec: None,
+            container: None,
+            bit_rate: None,
+            channels: None,
+            sample_rate: None,
+            copy_metadata: true,
+            copy_cover_art: true,
+            progress: None,
+        }
+    }
+
+    pub fn codec(mut self, codec: AudioCodec) -> TranscodeOptions {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Short name of the output container format, e.g. `ogg` or `matroska`
+    pub fn container(mut self, container: impl Into<String>) -> TranscodeOptions {
+        self.container = Some(container.into());
+        self
+    }
+
+    /// Target bit rate in bits per second, ignored by lossless codecs
+    pub fn bit_rate(mut self, bit_rate: u64) -> TranscodeOptions {
+        self.bit_rate = Some(bit_rate);
+        self
+    }
+
+    /// Remix to this number of channels
+    pub fn channels(mut self, channels: u16) -> TranscodeOptions {
+        self.channels = Some(channels);
+        self
+    }
+
+    /// Resample to this rate
+    pub fn sample_rate(mut self, sample_rate: u32) -> TranscodeOptions {
+        self.sample_rate = Some(sample_rate);
+        self
+    }
+
+    /// Copy the tags of the input, enabled by default. Tags of the input container such as
+    /// `encoder` or `major_brand` are left to the output container.
+    pub fn copy_metadata(mut self, copy_metadata: bool) -> TranscodeOptions {
+        self.copy_metadata = copy_metadata;
+        self
+    }
+
+    /// Copy the embedded cover picture of the input, enabled by default. Skipped if the output
+    /// container can't hold one.
+    pub fn copy_cover_art(mut self, copy_cover_art: bool) -> TranscodeOptions {
+        self.copy_cover_art = copy_cover_art;
+        self
+    }
+
+    /// Called after every decoded frame has been written
+    pub fn on_progress(mut self, progress: impl FnMut(Progress) + 'static) -> TranscodeOptions {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+}
+
+impl Default for TranscodeOptions {
+    fn default() -> TranscodeOptions {
+        TranscodeOptions::new()
+    }
+}
+
+impl fmt::Debug for TranscodeOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TranscodeOptions")
+            .field("codec", &self.codec)
+            .field("container", &self.container)
+            .field("bit_rate", &self.bit_rate)
+            .field("channels", &self.channels)
+            .field("sample_rate", &self.sample_rate)
+            .field("copy_metadata", &self.copy_metadata)
+            .field("copy_cover_art", &self.copy_cover_art)
+            .field("progress", &self.progress.is_some())
+            .finish()
+    }
+}
+
+/// How much of the input has been transcoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Time of the input decoded and encoded so far
+    pub position: Duration,
+    /// Duration of the input as stated by the container, if known
+    pub duration: Option<Duration>,
+}
+
+impl Progress {
+    /// Completed fraction between 0 and 1, if the duration is known
+    pub fn fraction(&self) -> Option<f64> {
+        self.duration
+            .filter(|duration| *duration > Duration::from_secs(0))
+            .map(|duration| (self.position.as_secs_f64() / duration.as_secs_f64()).min(1.0))
+    }
+}
+
+/// Decode the audio of `input` and encode it to `output`
+///
+/// Samples are resampled and remixed as needed by the output codec. To encode the same input
+/// at several bit rates, call this once per output. If decoding or encoding fails once `output`
+/// is created, it's removed rather than left cut short.
+///
+/// ```no_run
+/// use ffmpeg_decoder::{transcode, AudioCodec, TranscodeOptions};
+///
+/// for &bit_rate in &[64_000, 128_000] {
+///     let options = TranscodeOptions::new()
+///         .codec(AudioCodec::Opus)
+///         .bit_rate(bit_rate)
+///         .on_progress(|progress| {
+///             if let Some(fraction) = progress.fraction() {
+///                 println!("{:.0}%", fraction * 100.0);
+///             }
+///         });
+///
+///     transcode("input.flac", format!("output_{}.opus", bit_rate), options)?;
+/// }
+/// # Ok::<(), ffmpeg_decoder::Error>(())
+/// ```
+pub fn transcode(
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    mut options: TranscodeOptions,
+) -> Result<(), Error> {
+    let decoder = Decoder::open(input)?;
+
+    let mut builder = Encoder::builder(output, decoder._channels(), decoder._sample_rate());
+    if let Some(codec) = options.codec {
+        builder = builder.codec(codec);
+    }
+    if let Some(container) = options.container.take() {
+        builder = builder.container(container);
+    }
+    if let Some(bit_rate) = options.bit_rate {
+        builder = builder.bit_rate(bit_rate);
+    }
+    if let Some(channels) = options.channels {
+        builder = builder.output_channels(channels);
+    }
+    if let Some(sample_rate) = options.sample_rate {
+        builder = builder.output_sample_rate(sample_rate);
+    }
+    if options.copy_metadata {
+        builder = builder.metadata(without_container_tags(decoder.metadata()));
+    }
+    if options.copy_cover_art {
+        if let Some(cover_art) = decoder.cover_art() {
+            builder = builder.cover_art(cover_art);
+        }
+    }
+
+    let mut encoder = builder.open()?;
+
+    if let Err(error) = encode_all(decoder, &mut encoder, &mut options) {
+        if let Err(e) = encoder.abort() {
+            warn!("Could not remove incomplete output: {}", e);
+        }
+
+        return Err(error);
+    }
+
+    encoder.finish()
+}
+
+/// Write all decoded samples to `encoder`, leaving it unfinished
+fn encode_all(
+    mut decoder: Decoder,
+    encoder: &mut Encoder,
+    options: &mut TranscodeOptions,
+) -> Result<(), Error> {
+    let duration = decoder.duration();
+
+    while let Some(frame) = decoder.next_frame() {
+        encoder.write(&frame.samples)?;
+
+        if let Some(progress) = options.progress.as_mut() {
+            progress(Progress {
+                position: decoder.position().time,
+                duration,
+            });
+        }
+    }
+
+    match decoder.take_error() {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+fn without_container_tags(metadata: Metadata) -> Metadata {
+    let mut tags = Metadata::default();
+    for (key, value) in metadata.iter() {
+        if !CONTAINER_TAGS
+            .iter()
+            .any(|tag| tag.eq_ignore_ascii_case(key))
+        {
+            tags.insert(key, value);
+        }
+    }
+
+    tags
+}
@@ -0,0 +1,393 @@
+use crate::decoder::Decoder;
+use crate::error::Error;
+
+use ffmpeg_sys_next::av_get_default_channel_layout;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+/// Tail of the `KSDATAFORMAT_SUBTYPE_*` GUIDs, following the 4 byte format code
+const SUBFORMAT_GUID_TAIL: [u8; 12] = [
+    0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+];
+/// Speaker positions defined for `dwChannelMask`, which match ffmpegs first 18 `AV_CH_*` flags
+const SPEAKER_MASK: u64 = 0x3_FFFF;
+
+/// Sample format of the data written by a [`WavWriter`](struct.WavWriter.html)
+///
+/// Samples are always passed in as signed 16bit, wider formats are padded so the file can be
+/// processed by tools expecting them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WavSampleFormat {
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl WavSampleFormat {
+    fn bits_per_sample(self) -> u16 {
+        match self {
+            WavSampleFormat::Int16 => 16,
+            WavSampleFormat::Int24 => 24,
+            WavSampleFormat::Float32 => 32,
+        }
+    }
+
+    fn format_code(self) -> u16 {
+        match self {
+            WavSampleFormat::Int16 | WavSampleFormat::Int24 => WAVE_FORMAT_PCM,
+            WavSampleFormat::Float32 => WAVE_FORMAT_IEEE_FLOAT,
+        }
+    }
+}
+
+/// Layout of a wav file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavSpec {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub sample_format: WavSampleFormat,
+    /// Speaker positions as `AV_CH_*` flags, only written for more than 2 channels or samples
+    /// wider than 16bit. Defaults to the ffmpeg default layout for the number of channels.
+    pub channel_layout: Option<u64>,
+}
+
+impl WavSpec {
+    pub fn new(channels: u16, sample_rate: u32, sample_format: WavSampleFormat) -> WavSpec {
+        WavSpec {
+            channels,
+            sample_rate,
+            sample_format,
+            channel_layout: None,
+        }
+    }
+
+    /// Channels, rate and layout of the samples the decoder returns, written as signed 16bit
+    pub fn from_decoder(decoder: &Decoder) -> WavSpec {
+        WavSpec {
+            channels: decoder._channels(),
+            sample_rate: decoder._sample_rate(),
+            sample_format: WavSampleFormat::Int16,
+            channel_layout: Some(decoder._channel_layout()),
+        }
+    }
+
+    fn block_align(&self) -> u16 {
+        self.channels * self.sample_format.bits_per_sample() / 8
+    }
+
+    fn is_extensible(&self) -> bool {
+        self.channels > 2 || self.sample_format.bits_per_sample() > 16
+    }
+
+    fn channel_mask(&self) -> u32 {
+        let layout = self
+            .channel_layout
+            .filter(|layout| *layout != 0)
+            .unwrap_or_else(|| unsafe {
+                av_get_default_channel_layout(self.channels as i32) as u64
+            });
+
+        (layout & SPEAKER_MASK) as u32
+    }
+}
+
+/// Writes interleaved signed 16bit samples to a RIFF/WAVE file
+///
+/// The chunk sizes in the header are filled in by [`finish`](#method.finish), which must be
+/// called once all samples are written. Files with more than 2 channels or wider samples use
+/// `WAVE_FORMAT_EXTENSIBLE` to carry the speaker layout.
+///
+/// ```no_run
+/// use ffmpeg_decoder::{Decoder, WavSpec, WavWriter};
+///
+/// let mut decoder = Decoder::open("input.flac")?;
+///
+/// let mut writer = WavWriter::create("output.wav", WavSpec::from_decoder(&decoder))?;
+/// writer.write_decoder(&mut decoder)?;
+/// writer.finish()?;
+/// # Ok::<(), ffmpeg_decoder::Error>(())
+/// ```
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    spec: WavSpec,
+    data_size_offset: u64,
+    fact_offset: Option<u64>,
+    data_len: u64,
+    buffer: Vec<u8>,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(
+        path: impl AsRef<Path>,
+        spec: WavSpec,
+    ) -> Result<WavWriter<BufWriter<File>>, Error> {
+        let file = File::create(path)?;
+
+        WavWriter::new(BufWriter::new(file), spec)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Writes the header to `writer`, chunk sizes are left at 0 until finished
+    pub fn new(mut writer: W, spec: WavSpec) -> Result<WavWriter<W>, Error> {
+        let bits_per_sample = spec.sample_format.bits_per_sample();
+        let block_align = spec.block_align();
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"fmt ")?;
+        if spec.is_extensible() {
+            writer.write_all(&40u32.to_le_bytes())?;
+            writer.write_all(&WAVE_FORMAT_EXTENSIBLE.to_le_bytes())?;
+        } else {
+            writer.write_all(&16u32.to_le_bytes())?;
+            writer.write_all(&spec.sample_format.format_code().to_le_bytes())?;
+        }
+        writer.write_all(&spec.channels.to_le_bytes())?;
+        writer.write_all(&spec.sample_rate.to_le_bytes())?;
+        writer.write_all(&(spec.sample_rate * u32::from(block_align)).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&bits_per_sample.to_le_bytes())?;
+        if spec.is_extensible() {
+            writer.write_all(&22u16.to_le_bytes())?;
+            writer.write_all(&bits_per_sample.to_le_bytes())?;
+            writer.write_all(&spec.channel_mask().to_le_bytes())?;
+            writer.write_all(&u32::from(spec.sample_format.format_code()).to_le_bytes())?;
+            writer.write_all(&SUBFORMAT_GUID_TAIL)?;
+        }
+
+        // Non PCM formats need the number of sample frames
+        let fact_offset = if spec.sample_format == WavSampleFormat::Float32 {
+            writer.write_all(b"fact")?;
+            writer.write_all(&4u32.to_le_bytes())?;
+            let offset = writer.stream_position()?;
+            writer.write_all(&0u32.to_le_bytes())?;
+
+            Some(offset)
+        } else {
+            None
+        };
+
+        writer.write_all(b"data")?;
+        let data_size_offset = writer.stream_position()?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter {
+            writer,
+            spec,
+            data_size_offset,
+            fact_offset,
+            data_len: 0,
+            buffer: vec![],
+        })
+    }
+
+    pub fn spec(&self) -> WavSpec {
+        self.spec
+    }
+
+    /// Write interleaved samples. The length must be a multiple of the number of channels.
+    pub fn write_samples(&mut self, samples: &[i16]) -> Result<(), Error> {
+        self.buffer.clear();
+
+        match self.spec.sample_format {
+            WavSampleFormat::Int16 => {
+                for sample in samples {
+                    self.buffer.extend_from_slice(&sample.to_le_bytes());
+                }
+            }
+            WavSampleFormat::Int24 => {
+                for sample in samples {
+                    self.buffer
+                        .extend_from_slice(&(i32::from(*sample) << 8).to_le_bytes()[..3]);
+                }
+            }
+            WavSampleFormat::Float32 => {
+                for sample in samples {
+                    let sample = f32::from(*sample) / 32768.0;
+                    self.buffer.extend_from_slice(&sample.to_le_bytes());
+                }
+            }
+        }
+
+        self.writer.write_all(&self.buffer)?;
+        self.data_len += self.buffer.len() as u64;
+
+        Ok(())
+    }
+
+    /// Write all remaining samples of `decoder`
+    ///
+    /// Returns the error that stopped the decoder, if it didn't reach the end of the stream.
+    pub fn write_decoder(&mut self, decoder: &mut Decoder) -> Result<(), Error> {
+        while let Some(frame) = decoder.next_frame() {
+            self.write_samples(&frame.samples)?;
+        }
+
+        match decoder.take_error() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Fill in the chunk sizes and return the underlying writer
+    ///
+    /// Sizes are limited to 4 GiB by the format, larger files are written with the maximum size.
+    pub fn finish(mut self) -> Result<W, Error> {
+        // Chunks are padded to an even size
+        if self.data_len % 2 != 0 {
+            self.writer.write_all(&[0])?;
+        }
+        let end = self.writer.stream_position()?;
+
+        let riff_size = clamp_u32(end - 8);
+        let data_size = clamp_u32(self.data_len);
+        let num_frames = clamp_u32(self.data_len / u64::from(self.spec.block_align().max(1)));
+
+        write_u32_at(&mut self.writer, 4, riff_size)?;
+        write_u32_at(&mut self.writer, self.data_size_offset, data_size)?;
+        if let Some(fact_offset) = self.fact_offset {
+            write_u32_at(&mut self.writer, fact_offset, num_frames)?;
+        }
+
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+fn write_u32_at<W: Write + Seek>(writer: &mut W, offset: u64, value: u32) -> io::Result<()> {
+    writer.seek(SeekFrom::Start(offset))?;
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn clamp_u32(value: u64) -> u32 {
+    value.min(u64::from(u32::MAX)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn write(spec: WavSpec, samples: &[i16]) -> Vec<u8> {
+        let mut writer = WavWriter::new(Cursor::new(vec![]), spec).unwrap();
+        writer.write_samples(samples).unwrap();
+
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        let mut value = [0; 4];
+        value.copy_from_slice(&bytes[offset..offset + 4]);
+
+        u32::from_le_bytes(value)
+    }
+
+    #[test]
+    fn pcm_16bit_stereo_header() {
+        let spec = WavSpec::new(2, 44100, WavSampleFormat::Int16);
+        let bytes = write(spec, &[1, -1, 0x1234, -32768]);
+
+        let mut expected = vec![];
+        expected.extend_from_slice(b"RIFF");
+        expected.extend_from_slice(&44u32.to_le_bytes());
+        expected.extend_from_slice(b"WAVEfmt ");
+        expected.extend_from_slice(&16u32.to_le_bytes());
+        expected.extend_from_slice(&1u16.to_le_bytes());
+        expected.extend_from_slice(&2u16.to_le_bytes());
+        expected.extend_from_slice(&44100u32.to_le_bytes());
+        expected.extend_from_slice(&176_400u32.to_le_bytes());
+        expected.extend_from_slice(&4u16.to_le_bytes());
+        expected.extend_from_slice(&16u16.to_le_bytes());
+        expected.extend_from_slice(b"data");
+        expected.extend_from_slice(&8u32.to_le_bytes());
+        expected.extend_from_slice(&[0x01, 0x00, 0xFF, 0xFF, 0x34, 0x12, 0x00, 0x80]);
+
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn extensible_24bit_surround() {
+        let spec = WavSpec {
+            channel_layout: Some(0x60F),
+            ..WavSpec::new(6, 48000, WavSampleFormat::Int24)
+        };
+        let bytes = write(spec, &[0x0102, -1, 0, 0, 0, 0]);
+
+        assert_eq!(&bytes[12..16], b"fmt ");
+        assert_eq!(u32_at(&bytes, 16), 40);
+        assert_eq!(u16_at(&bytes, 20), WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(u16_at(&bytes, 22), 6);
+        assert_eq!(u32_at(&bytes, 24), 48000);
+        assert_eq!(u32_at(&bytes, 28), 48000 * 18);
+        assert_eq!(u16_at(&bytes, 32), 18);
+        assert_eq!(u16_at(&bytes, 34), 24);
+        // cbSize, wValidBitsPerSample, dwChannelMask and the KSDATAFORMAT_SUBTYPE_PCM GUID
+        assert_eq!(u16_at(&bytes, 36), 22);
+        assert_eq!(u16_at(&bytes, 38), 24);
+        assert_eq!(u32_at(&bytes, 40), 0x60F);
+        assert_eq!(
+            &bytes[44..60],
+            &[
+                0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38,
+                0x9B, 0x71
+            ]
+        );
+
+        assert_eq!(&bytes[60..64], b"data");
+        assert_eq!(u32_at(&bytes, 64), 18);
+        assert_eq!(&bytes[68..74], &[0x00, 0x02, 0x01, 0x00, 0xFF, 0xFF]);
+        assert_eq!(bytes.len(), 68 + 18);
+        assert_eq!(u32_at(&bytes, 4), bytes.len() as u32 - 8);
+    }
+
+    #[test]
+    fn odd_data_is_padded() {
+        let spec = WavSpec {
+            channel_layout: Some(0x4),
+            ..WavSpec::new(1, 8000, WavSampleFormat::Int24)
+        };
+        let bytes = write(spec, &[1]);
+
+        assert_eq!(u32_at(&bytes, 64), 3);
+        assert_eq!(&bytes[68..], &[0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(u32_at(&bytes, 4), bytes.len() as u32 - 8);
+    }
+
+    #[test]
+    fn float_with_fact_chunk() {
+        let spec = WavSpec {
+            channel_layout: Some(0x4),
+            ..WavSpec::new(1, 8000, WavSampleFormat::Float32)
+        };
+        let bytes = write(spec, &[16384, -32768, 0]);
+
+        assert_eq!(u16_at(&bytes, 20), WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(u32_at(&bytes, 40), 0x4);
+        assert_eq!(u32_at(&bytes, 44), u32::from(WAVE_FORMAT_IEEE_FLOAT));
+
+        assert_eq!(&bytes[60..64], b"fact");
+        assert_eq!(u32_at(&bytes, 64), 4);
+        assert_eq!(u32_at(&bytes, 68), 3);
+
+        assert_eq!(&bytes[72..76], b"data");
+        assert_eq!(u32_at(&bytes, 76), 12);
+        assert_eq!(&bytes[80..84], &0.5f32.to_le_bytes());
+        assert_eq!(&bytes[84..88], &(-1.0f32).to_le_bytes());
+        assert_eq!(u32_at(&bytes, 4), bytes.len() as u32 - 8);
+    }
+}
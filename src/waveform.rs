@@ -0,0 +1,315 @@
+use crate::decoder::Decoder;
+use crate::error::Error;
+use crate::frame::duration_to_samples;
+
+use std::path::Path;
+
+/// Blocks per bucket kept by `WaveformMode::FromDecodedLength`, at least half of which remain after
+/// merging
+const BLOCKS_PER_BUCKET: usize = 32;
+
+/// How samples are assigned to the buckets of a [`Waveform`](struct.Waveform.html). Either way
+/// the stream is decoded a frame at a time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WaveformMode {
+    /// Buckets hold the same number of samples, based on the duration stated by the container.
+    /// Samples past that duration are added to the last bucket. Falls back to
+    /// `FromDecodedLength` if the duration is unknown.
+    #[default]
+    FromDuration,
+    /// Buckets are placed once the stream is decoded, based on the number of samples decoded.
+    /// Samples are summarized in small blocks until then, so bucket boundaries are off by up to
+    /// 1/16 of a bucket.
+    FromDecodedLength,
+}
+
+/// Peak overview of a stream, as `[channel][bucket]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Waveform {
+    pub sample_rate: u32,
+    /// Number of samples per channel that were decoded
+    pub num_samples: u64,
+    pub channels: Vec<Vec<WaveformBucket>>,
+}
+
+/// Summary of the samples of one channel in a bucket, scaled to `-1.0..=1.0`. All zero if the
+/// bucket is empty.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WaveformBucket {
+    pub min: f32,
+    pub max: f32,
+    pub rms: f32,
+}
+
+/// Decode the whole stream and summarize it in `buckets` buckets per channel. Frames are
+/// summarized as they're decoded, without iterating sample by sample.
+pub fn waveform(path: impl AsRef<Path>, buckets: usize) -> Result<Waveform, Error> {
+    waveform_with(path, buckets, WaveformMode::default())
+}
+
+/// Like [`waveform`](fn.waveform.html), placing buckets as `mode` says. Decoding is frame by frame
+/// in every mode.
+pub fn waveform_with(
+    path: impl AsRef<Path>,
+    buckets: usize,
+    mode: WaveformMode,
+) -> Result<Waveform, Error> {
+    let mut decoder = Decoder::open(path)?;
+
+    let channels = decoder._channels().max(1) as usize;
+    let sample_rate = decoder._sample_rate();
+    let buckets = buckets.max(1);
+
+    let expected_samples = decoder
+        .duration()
+        .map(|duration| duration_to_samples(duration, sample_rate));
+
+    let frames = std::iter::from_fn(|| decoder.next_frame().map(|frame| frame.samples));
+
+    let (accumulators, num_samples) = match (mode, expected_samples) {
+        (WaveformMode::FromDuration, Some(expected_samples)) if expected_samples > 0 => {
+            duration_buckets(frames, channels, buckets, expected_samples)
+        }
+        _ => decoded_length_buckets(frames, channels, buckets),
+    };
+
+    if let Some(error) = decoder.take_error() {
+        return Err(error);
+    }
+
+    Ok(Waveform {
+        sample_rate,
+        num_samples,
+        channels: (0..channels)
+            .map(|channel| {
+                accumulators
+                    .iter()
+                    .map(|bucket| bucket[channel].bucket())
+                    .collect()
+            })
+            .collect(),
+    })
+}
+
+/// Splits frames at bucket boundaries. Samples past the expected length are added to the
+/// last bucket.
+fn duration_buckets(
+    frames: impl Iterator<Item = Vec<i16>>,
+    channels: usize,
+    buckets: usize,
+    expected_samples: u64,
+) -> (Vec<Vec<Accumulator>>, u64) {
+    let samples_per_bucket = expected_samples.div_ceil(buckets as u64).max(1);
+
+    let mut accumulators = vec![vec![Accumulator::default(); channels]; buckets];
+
+    let mut position = 0;
+    for samples in frames {
+        let num_samples = samples.len() / channels;
+
+        let mut offset = 0;
+        while offset < num_samples {
+            let bucket = ((position / samples_per_bucket) as usize).min(buckets - 1);
+            let len = if bucket == buckets - 1 {
+                num_samples - offset
+            } else {
+                let bucket_end = (bucket as u64 + 1) * samples_per_bucket;
+                ((bucket_end - position) as usize).min(num_samples - offset)
+            };
+
+            add_samples(
+                &mut accumulators[bucket],
+                &samples[offset * channels..(offset + len) * channels],
+            );
+
+            offset += len;
+            position += len as u64;
+        }
+    }
+
+    (accumulators, position)
+}
+
+/// Summarizes frames in blocks of equal size, then merges the blocks into buckets once the
+/// length is known. Whenever there are too many blocks, neighbouring blocks are merged and
+/// the block size doubles, so memory use stays bounded.
+fn decoded_length_buckets(
+    frames: impl Iterator<Item = Vec<i16>>,
+    channels: usize,
+    buckets: usize,
+) -> (Vec<Vec<Accumulator>>, u64) {
+    let max_blocks = buckets * BLOCKS_PER_BUCKET;
+
+    let mut blocks: Vec<Vec<Accumulator>> = vec![];
+    let mut block_size = 1;
+
+    let mut position = 0;
+    for samples in frames {
+        let num_samples = samples.len() / channels;
+
+        let mut offset = 0;
+        while offset < num_samples {
+            if position % block_size == 0 {
+                if blocks.len() == max_blocks {
+                    blocks = merge_pairs(blocks);
+                    block_size *= 2;
+                }
+                blocks.push(vec![Accumulator::default(); channels]);
+            }
+
+            let len = ((block_size - position % block_size) as usize).min(num_samples - offset);
+
+            add_samples(
+                blocks.last_mut().unwrap(),
+                &samples[offset * channels..(offset + len) * channels],
+            );
+
+            offset += len;
+            position += len as u64;
+        }
+    }
+
+    let mut accumulators = vec![vec![Accumulator::default(); channels]; buckets];
+
+    // Blocks go to the bucket their center falls into
+    for (index, block) in blocks.iter().enumerate() {
+        let center = index as u64 * block_size + block_size / 2;
+        let bucket = ((center * buckets as u64 / position.max(1)) as usize).min(buckets - 1);
+
+        for (accumulator, block) in accumulators[bucket].iter_mut().zip(block) {
+            accumulator.merge(block);
+        }
+    }
+
+    (accumulators, position)
+}
+
+/// Adds interleaved `samples` to the accumulators of their channels
+fn add_samples(accumulators: &mut [Accumulator], samples: &[i16]) {
+    for samples in samples.chunks_exact(accumulators.len()) {
+        for (accumulator, sample) in accumulators.iter_mut().zip(samples) {
+            accumulator.add(*sample);
+        }
+    }
+}
+
+fn merge_pairs(blocks: Vec<Vec<Accumulator>>) -> Vec<Vec<Accumulator>> {
+    blocks
+        .chunks(2)
+        .map(|pair| {
+            let mut merged = pair[0].clone();
+            for block in &pair[1..] {
+                for (accumulator, other) in merged.iter_mut().zip(block) {
+                    accumulator.merge(other);
+                }
+            }
+            merged
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Accumulator {
+    min: i16,
+    max: i16,
+    sum_squares: f64,
+    count: u64,
+}
+
+impl Default for Accumulator {
+    fn default() -> Accumulator {
+        Accumulator {
+            min: i16::MAX,
+            max: i16::MIN,
+            sum_squares: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Accumulator {
+    fn add(&mut self, sample: i16) {
+        self.min = self.min.min(sample);
+        self.max = self.max.max(sample);
+        self.sum_squares += f64::from(sample) * f64::from(sample);
+        self.count += 1;
+    }
+
+    fn merge(&mut self, other: &Accumulator) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum_squares += other.sum_squares;
+        self.count += other.count;
+    }
+
+    fn bucket(&self) -> WaveformBucket {
+        if self.count == 0 {
+            return WaveformBucket::default();
+        }
+
+        WaveformBucket {
+            min: f32::from(self.min) / 32768.0,
+            max: f32::from(self.max) / 32768.0,
+            rms: ((self.sum_squares / self.count as f64).sqrt() / 32768.0) as f32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mono frames of `frame_size` samples, each sample being its position
+    fn frames(num_samples: usize, frame_size: usize) -> Vec<Vec<i16>> {
+        (0..num_samples as i16)
+            .collect::<Vec<_>>()
+            .chunks(frame_size)
+            .map(<[i16]>::to_vec)
+            .collect()
+    }
+
+    fn ranges(accumulators: &[Vec<Accumulator>]) -> Vec<(i16, i16)> {
+        accumulators
+            .iter()
+            .map(|bucket| (bucket[0].min, bucket[0].max))
+            .collect()
+    }
+
+    #[test]
+    fn from_duration_splits_frames_at_bucket_boundaries() {
+        let (accumulators, num_samples) = duration_buckets(frames(100, 30).into_iter(), 1, 4, 100);
+
+        assert_eq!(num_samples, 100);
+        assert_eq!(
+            ranges(&accumulators),
+            vec![(0, 24), (25, 49), (50, 74), (75, 99)]
+        );
+    }
+
+    #[test]
+    fn from_decoded_length_fills_more_buckets_than_frames() {
+        let (accumulators, num_samples) =
+            decoded_length_buckets(frames(100, 50).into_iter(), 1, 10);
+
+        assert_eq!(num_samples, 100);
+        assert_eq!(
+            ranges(&accumulators),
+            (0..10).map(|n| (n * 10, n * 10 + 9)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn from_decoded_length_merges_blocks() {
+        let num_samples = 10_000;
+        let (accumulators, _) = decoded_length_buckets(frames(num_samples, 1152).into_iter(), 1, 8);
+
+        let tolerance = (num_samples / 8 / 16) as i16;
+        for (bucket, (min, max)) in ranges(&accumulators).into_iter().enumerate() {
+            let start = (bucket * num_samples / 8) as i16;
+            let end = ((bucket + 1) * num_samples / 8) as i16 - 1;
+
+            assert!((min - start).abs() <= tolerance, "{} {}", min, start);
+            assert!((max - end).abs() <= tolerance, "{} {}", max, end);
+        }
+    }
+}